@@ -0,0 +1,108 @@
+/*
+Fixed size circular buffer that keeps the most recent part of the replication stream.
+Positions in the buffer are derived from replication offsets, so a replica that reconnects
+can continue from its offset, as long as that offset has not been overwritten yet.
+ */
+pub(crate) struct Backlog {
+    buffer: Vec<u8>,
+    start_offset: usize,
+    end_offset: usize,
+}
+impl Backlog {
+    pub fn new(size: usize, offset: usize) -> Self {
+        assert!(size > 0, "backlog size should not be zero");
+        Self {
+            buffer: vec![0; size],
+            start_offset: offset,
+            end_offset: offset,
+        }
+    }
+    pub fn append(&mut self, data: &[u8]) {
+        let size = self.buffer.len();
+        // if the data is larger than the whole buffer, only its tail is going to survive anyway
+        let skip = data.len().saturating_sub(size);
+        self.end_offset += skip;
+        for chunk in data[skip..].chunks(size) {
+            let position = self.end_offset % size;
+            let first_part = chunk.len().min(size - position);
+            self.buffer[position..position + first_part].copy_from_slice(&chunk[..first_part]);
+            self.buffer[..chunk.len() - first_part].copy_from_slice(&chunk[first_part..]);
+            self.end_offset += chunk.len();
+        }
+        self.start_offset = self.start_offset.max(self.end_offset.saturating_sub(size));
+    }
+    pub fn read_from(&self, offset: usize) -> Option<Vec<u8>> {
+        if (offset < self.start_offset) || (offset > self.end_offset) {
+            return None;
+        }
+        let size = self.buffer.len();
+        let position = offset % size;
+        let length = self.end_offset - offset;
+        let first_part = length.min(size - position);
+        let mut result = Vec::with_capacity(length);
+        result.extend_from_slice(&self.buffer[position..position + first_part]);
+        result.extend_from_slice(&self.buffer[..length - first_part]);
+        Some(result)
+    }
+    pub fn get_size(&self) -> usize {
+        self.buffer.len()
+    }
+    pub fn get_start_offset(&self) -> usize {
+        self.start_offset
+    }
+    pub fn get_length(&self) -> usize {
+        self.end_offset - self.start_offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backlog_reads_appended_data() {
+        let mut backlog = Backlog::new(8, 0);
+        assert_eq!(backlog.read_from(0), Some(Vec::new()));
+        backlog.append(b"abc");
+        backlog.append(b"de");
+        assert_eq!(backlog.read_from(0), Some(b"abcde".to_vec()));
+        assert_eq!(backlog.read_from(3), Some(b"de".to_vec()));
+        assert_eq!(backlog.read_from(5), Some(Vec::new()));
+        assert_eq!(backlog.read_from(6), None);
+        assert_eq!(backlog.get_length(), 5);
+    }
+
+    #[test]
+    fn backlog_wraps_around() {
+        let mut backlog = Backlog::new(8, 0);
+        backlog.append(b"abcdef");
+        backlog.append(b"ghijk");
+        assert_eq!(backlog.get_start_offset(), 3);
+        assert_eq!(backlog.get_length(), 8);
+        assert_eq!(backlog.read_from(2), None);
+        assert_eq!(backlog.read_from(3), Some(b"defghijk".to_vec()));
+        assert_eq!(backlog.read_from(9), Some(b"jk".to_vec()));
+    }
+
+    #[test]
+    fn backlog_keeps_the_tail_of_large_data() {
+        let mut backlog = Backlog::new(4, 0);
+        backlog.append(b"ab");
+        backlog.append(b"0123456789");
+        assert_eq!(backlog.get_start_offset(), 8);
+        assert_eq!(backlog.read_from(8), Some(b"6789".to_vec()));
+        assert_eq!(backlog.read_from(7), None);
+    }
+
+    #[test]
+    fn backlog_starts_from_any_offset() {
+        // after a full resync the offsets continue from master's offset
+        let mut backlog = Backlog::new(8, 1_000_003);
+        assert_eq!(backlog.read_from(1_000_002), None);
+        backlog.append(b"abcdefghij");
+        assert_eq!(backlog.get_start_offset(), 1_000_005);
+        assert_eq!(backlog.read_from(1_000_005), Some(b"cdefghij".to_vec()));
+        assert_eq!(backlog.read_from(1_000_011), Some(b"ij".to_vec()));
+        assert_eq!(backlog.read_from(1_000_014), None);
+    }
+}
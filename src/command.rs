@@ -1,21 +1,26 @@
-pub(crate) type CommandRaw = (Vec<Vec<u8>>, usize);
+pub(crate) type CommandRaw = Vec<Vec<u8>>;
 
 #[derive(Clone, Debug)]
 pub(crate) struct Command {
-    pub byte_size: usize,
     pub name: String,
     pub raw: Vec<Vec<u8>>,
 }
 impl Command {
-    pub fn new((raw, byte_size): CommandRaw) -> Option<Self> {
-        if raw.len() == 0 {
+    pub fn new(raw: CommandRaw) -> Option<Self> {
+        if raw.is_empty() {
             eprintln!("received a command of size 0");
             return None;
         }
-        let name = normalize_name(&raw[0])?;
-        let res = Command{ byte_size, name, raw };
+        // names that are not valid utf-8 can't match any command, so they are reported as unknown
+        let name = String::from_utf8_lossy(&raw[0]).to_ascii_uppercase();
+        let res = Command{ name, raw };
         Some(res)
     }
+    /// Replaces the name that the client has used with the canonical one
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.to_string();
+        self.raw[0] = name.as_bytes().to_vec();
+    }
     pub fn get_args(&self) -> &[Vec<u8>] {
         &self.raw[1..]
     }
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+
+/*
+Static description of the supported commands.
+Arity follows the redis convention: it includes the command name itself,
+and a negative value means "at least that many" arguments.
+Key positions are the same as in the COMMAND reply: the index of the first key, the last one
+(negative counts from the end), and the step between them, or all zeros when the command has no keys.
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CommandFlag {
+    /// modifies the data
+    Write,
+    /// only reads the data
+    ReadOnly,
+    /// may increase the memory usage
+    DenyOom,
+    /// administrative command, e.g. replication or configuration
+    Admin,
+    /// pub/sub related command
+    PubSub,
+    /// not allowed in scripts
+    NoScript,
+    /// allowed while the data is being loaded
+    Loading,
+    /// allowed on a replica that has lost its link to master
+    Stale,
+    /// can't be queued after MULTI
+    NoMulti,
+}
+impl CommandFlag {
+    pub fn name(self) -> &'static str {
+        match self {
+            Write => "write",
+            ReadOnly => "readonly",
+            DenyOom => "denyoom",
+            Admin => "admin",
+            PubSub => "pubsub",
+            NoScript => "noscript",
+            Loading => "loading",
+            Stale => "stale",
+            NoMulti => "no_multi",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum AclCategory {
+    Keyspace,
+    Read,
+    Write,
+    String,
+    Stream,
+    PubSub,
+    Admin,
+    Fast,
+    Slow,
+    Dangerous,
+    Connection,
+    Transaction,
+}
+impl AclCategory {
+    pub fn name(self) -> &'static str {
+        match self {
+            AclCategory::Keyspace => "@keyspace",
+            AclCategory::Read => "@read",
+            AclCategory::Write => "@write",
+            AclCategory::String => "@string",
+            AclCategory::Stream => "@stream",
+            AclCategory::PubSub => "@pubsub",
+            AclCategory::Admin => "@admin",
+            AclCategory::Fast => "@fast",
+            AclCategory::Slow => "@slow",
+            AclCategory::Dangerous => "@dangerous",
+            AclCategory::Connection => "@connection",
+            AclCategory::Transaction => "@transaction",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct CommandSpec {
+    pub name: &'static str,
+    pub arity: i64,
+    pub flags: &'static [CommandFlag],
+    pub first_key: i64,
+    pub last_key: i64,
+    pub key_step: i64,
+    pub categories: &'static [AclCategory],
+}
+impl CommandSpec {
+    /// `size` is the number of elements in the command, including its name
+    pub fn check_arity(&self, size: usize) -> bool {
+        let size = size as i64;
+        if self.arity < 0 {
+            size >= -self.arity
+        } else {
+            size == self.arity
+        }
+    }
+    pub fn has_flag(&self, flag: CommandFlag) -> bool {
+        self.flags.contains(&flag)
+    }
+}
+
+use CommandFlag::*;
+use AclCategory as Acl;
+
+const NO_KEYS: (i64, i64, i64) = (0, 0, 0);
+const ONE_KEY: (i64, i64, i64) = (1, 1, 1);
+const ALL_KEYS: (i64, i64, i64) = (1, -1, 1);
+
+const fn spec(
+    name: &'static str,
+    arity: i64,
+    flags: &'static [CommandFlag],
+    keys: (i64, i64, i64),
+    categories: &'static [AclCategory],
+) -> CommandSpec {
+    let (first_key, last_key, key_step) = keys;
+    CommandSpec { name, arity, flags, first_key, last_key, key_step, categories }
+}
+
+static COMMAND_TABLE: &[CommandSpec] = &[
+    spec("PING", -1, &[], NO_KEYS, &[Acl::Fast, Acl::Connection]),
+    spec("ECHO", 2, &[], NO_KEYS, &[Acl::Fast, Acl::Connection]),
+    spec("GET", 2, &[ReadOnly], ONE_KEY, &[Acl::Read, Acl::String, Acl::Fast]),
+    spec("SET", -3, &[Write, DenyOom], ONE_KEY, &[Acl::Write, Acl::String, Acl::Slow]),
+    spec("XADD", -5, &[Write, DenyOom], ONE_KEY, &[Acl::Write, Acl::Stream, Acl::Fast]),
+    spec("INCR", 2, &[Write, DenyOom], ONE_KEY, &[Acl::Write, Acl::String, Acl::Fast]),
+    spec("DEL", -2, &[Write], ALL_KEYS, &[Acl::Keyspace, Acl::Write, Acl::Slow]),
+    spec("EXPIRE", -3, &[Write], ONE_KEY, &[Acl::Keyspace, Acl::Write, Acl::Fast]),
+    spec("PEXPIRE", -3, &[Write], ONE_KEY, &[Acl::Keyspace, Acl::Write, Acl::Fast]),
+    spec("EXPIREAT", -3, &[Write], ONE_KEY, &[Acl::Keyspace, Acl::Write, Acl::Fast]),
+    spec("PEXPIREAT", -3, &[Write], ONE_KEY, &[Acl::Keyspace, Acl::Write, Acl::Fast]),
+    spec("KEYS", 2, &[ReadOnly], NO_KEYS, &[Acl::Keyspace, Acl::Read, Acl::Slow, Acl::Dangerous]),
+    spec("TYPE", 2, &[ReadOnly], ONE_KEY, &[Acl::Keyspace, Acl::Read, Acl::Fast]),
+    spec("INFO", -1, &[Loading, Stale], NO_KEYS, &[Acl::Slow, Acl::Dangerous]),
+    spec("CONFIG", -2, &[Admin, NoScript, Loading, Stale], NO_KEYS, &[Acl::Admin, Acl::Slow, Acl::Dangerous]),
+    spec("ROLE", 1, &[NoScript, Loading, Stale], NO_KEYS, &[Acl::Admin, Acl::Fast, Acl::Dangerous]),
+    spec("COMMAND", -1, &[Loading, Stale], NO_KEYS, &[Acl::Slow, Acl::Connection]),
+    spec("REPLCONF", -1, &[Admin, NoScript, Loading, Stale], NO_KEYS, &[Acl::Admin, Acl::Slow, Acl::Dangerous]),
+    spec("PSYNC", -3, &[Admin, NoScript, NoMulti], NO_KEYS, &[Acl::Admin, Acl::Slow, Acl::Dangerous]),
+    spec("WAIT", 3, &[NoScript], NO_KEYS, &[Acl::Slow, Acl::Connection]),
+    spec("REPLICAOF", 3, &[Admin, NoScript, Stale], NO_KEYS, &[Acl::Admin, Acl::Slow, Acl::Dangerous]),
+    spec("SLAVEOF", 3, &[Admin, NoScript, Stale], NO_KEYS, &[Acl::Admin, Acl::Slow, Acl::Dangerous]),
+    spec("MULTI", 1, &[NoScript, Loading, Stale, NoMulti], NO_KEYS, &[Acl::Fast, Acl::Transaction]),
+    spec("EXEC", 1, &[NoScript, Loading, Stale], NO_KEYS, &[Acl::Slow, Acl::Transaction]),
+    spec("DISCARD", 1, &[NoScript, Loading, Stale], NO_KEYS, &[Acl::Fast, Acl::Transaction]),
+    spec("WATCH", -2, &[NoScript, Loading, Stale, NoMulti], ALL_KEYS, &[Acl::Fast, Acl::Transaction]),
+    spec("UNWATCH", 1, &[NoScript, Loading, Stale], NO_KEYS, &[Acl::Fast, Acl::Transaction]),
+    spec("SUBSCRIBE", -2, &[PubSub, NoScript, Loading, Stale], NO_KEYS, &[Acl::PubSub, Acl::Slow]),
+    spec("UNSUBSCRIBE", -1, &[PubSub, NoScript, Loading, Stale], NO_KEYS, &[Acl::PubSub, Acl::Slow]),
+    spec("PSUBSCRIBE", -2, &[PubSub, NoScript, Loading, Stale], NO_KEYS, &[Acl::PubSub, Acl::Slow]),
+    spec("PUNSUBSCRIBE", -1, &[PubSub, NoScript, Loading, Stale], NO_KEYS, &[Acl::PubSub, Acl::Slow]),
+    spec("PUBLISH", 3, &[PubSub, Loading, Stale], NO_KEYS, &[Acl::PubSub, Acl::Fast]),
+    spec("PUBSUB", -2, &[PubSub, Loading, Stale], NO_KEYS, &[Acl::PubSub, Acl::Slow]),
+    // shard channels are treated as keys, so that cluster-aware clients can route them
+    spec("SSUBSCRIBE", -2, &[PubSub, NoScript, Loading, Stale], ALL_KEYS, &[Acl::PubSub, Acl::Slow]),
+    spec("SUNSUBSCRIBE", -1, &[PubSub, NoScript, Loading, Stale], ALL_KEYS, &[Acl::PubSub, Acl::Slow]),
+    spec("SPUBLISH", 3, &[PubSub, Loading], ONE_KEY, &[Acl::PubSub, Acl::Fast]),
+    spec("HELLO", -1, &[NoScript, Loading, Stale], NO_KEYS, &[Acl::Fast, Acl::Connection]),
+    spec("QUIT", -1, &[NoScript, Loading, Stale], NO_KEYS, &[Acl::Fast, Acl::Connection]),
+    spec("RESET", 1, &[NoScript, Loading, Stale], NO_KEYS, &[Acl::Fast, Acl::Connection]),
+];
+
+/// `name` should already be normalized to upper case
+pub(crate) fn lookup_command(name: &str) -> Option<&'static CommandSpec> {
+    COMMAND_TABLE.iter().find(|x| x.name == name)
+}
+
+pub(crate) fn all_commands() -> &'static [CommandSpec] {
+    COMMAND_TABLE
+}
+
+/// The names that clients use for the commands, after they were changed with rename-command.
+/// Master and replicas always talk to each other with the canonical names.
+#[derive(Debug, Default)]
+pub(crate) struct CommandNames {
+    /// canonical name -> the name that clients have to use instead, or None if the command is disabled
+    renamed: HashMap<&'static str, Option<String>>,
+}
+impl CommandNames {
+    /// Each pair is a canonical name and a new one, an empty new name disables the command
+    pub fn new(renames: &[(String, String)]) -> Self {
+        let mut result = Self::default();
+        for (name, new_name) in renames {
+            let Some(spec) = lookup_command(&name.to_ascii_uppercase()) else {
+                eprintln!("can't rename unknown command {name}, ignoring it");
+                continue;
+            };
+            let new_name = new_name.to_ascii_uppercase();
+            if result.lookup(&new_name).is_some() {
+                eprintln!("can't rename {name} to {new_name}, the command with this name already exists");
+                continue;
+            }
+            result.renamed.insert(spec.name, Some(new_name).filter(|x| !x.is_empty()));
+        }
+        result
+    }
+    /// `name` should already be normalized to upper case
+    pub fn lookup(&self, name: &str) -> Option<&'static CommandSpec> {
+        let renamed = self.renamed.iter()
+            .find(|(_, new_name)| new_name.as_deref() == Some(name));
+        if let Some((canonical_name, _)) = renamed {
+            return lookup_command(canonical_name);
+        }
+        lookup_command(name).filter(|x| !self.renamed.contains_key(x.name))
+    }
+    /// None if the command is disabled
+    pub fn get_name(&self, spec: &CommandSpec) -> Option<&str> {
+        match self.renamed.get(spec.name) {
+            Some(new_name) => new_name.as_deref(),
+            None => Some(spec.name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(renames: &[(&str, &str)]) -> CommandNames {
+        let renames: Vec<_> = renames.iter()
+            .map(|(name, new_name)| (name.to_string(), new_name.to_string()))
+            .collect();
+        CommandNames::new(&renames)
+    }
+
+    #[test]
+    fn renamed_command_is_found_by_the_new_name_only() {
+        let names = names(&[("config", "my-config")]);
+        let spec = names.lookup("MY-CONFIG").expect("renamed command should be found");
+        assert_eq!(spec.name, "CONFIG");
+        assert_eq!(names.get_name(spec), Some("MY-CONFIG"));
+        assert!(names.lookup("CONFIG").is_none());
+        // the others keep their names
+        assert_eq!(names.lookup("GET").map(|x| x.name), Some("GET"));
+    }
+
+    #[test]
+    fn command_renamed_to_empty_name_is_disabled() {
+        let names = names(&[("keys", "")]);
+        assert!(names.lookup("KEYS").is_none());
+        assert!(names.lookup("").is_none());
+        let spec = lookup_command("KEYS").unwrap();
+        assert_eq!(names.get_name(spec), None);
+    }
+}
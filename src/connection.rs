@@ -1,25 +1,86 @@
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
 use std::sync::Arc;
-use std::sync::atomic::Ordering;
-use tokio::io::{AsyncWriteExt, BufReader};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::net::TcpStream;
 use tokio::select;
-use tokio::sync::broadcast::error::RecvError;
-use tokio::sync::broadcast::Receiver;
+use tokio::time::{interval, MissedTickBehavior};
 use crate::command::Command;
-use crate::handlers::{handle_command, handle_command_ignore_invalid, psync, HandleError};
-use crate::resp::{read_command, write_command, write_simple_error};
+use crate::command_table::{lookup_command, CommandSpec};
+use crate::handlers::{exec_replicated_transaction, handle_command, handle_command_ignore_invalid, psync, write_ack, HandleError};
+use crate::output_buffer::{OutputRecvError, ReplicaReceiver};
+use crate::pubsub::Subscriber;
+use crate::resp::{encode_reply, read_command, read_command_with_bytes, write_raw, Protocol, ReadError, Reply};
 use crate::server::Server;
 use crate::transaction::Transaction;
 
+const ACK_INTERVAL: Duration = Duration::from_secs(1);
+
 pub(crate) struct Connection {
+    pub id: usize,
     pub stream: BufReader<TcpStream>,
     pub server: Arc<Server>,
     pub kind: ConnectionKind,
+    /// the port that a replica has reported during the handshake
+    pub listening_port: Option<u16>,
+    /// the replica can receive the file without knowing its size in advance
+    pub supports_eof: bool,
+    /// pub/sub subscriptions, created by the first (P)SUBSCRIBE
+    pub subscriber: Option<Subscriber>,
+    pub protocol: Protocol,
+    /// set by HELLO SETNAME
+    pub name: Option<Vec<u8>>,
+    /// encoded replies, that are written all together once there are no more pipelined commands
+    output: Vec<u8>,
 }
 impl Connection {
-    pub fn can_replicate(&self) -> bool {
-        self.replicated_offset_ref().is_some()
+    pub fn can_write(&self) -> bool {
+        matches!(self.kind, ConnectionKind::ServerMasterConnectionExternal{..})
+            || self.is_from_master()
+    }
+    /// Renamed commands are only for the clients, master and replicas use the canonical names
+    pub fn lookup_command(&self, name: &str) -> Option<&'static CommandSpec> {
+        match self.kind {
+            ConnectionKind::ServerSlaveConnectionMaster{..} | ConnectionKind::ServerMasterConnectionSlave{..} => lookup_command(name),
+            _ => self.server.command_names.lookup(name),
+        }
+    }
+    pub fn is_from_master(&self) -> bool {
+        matches!(self.kind, ConnectionKind::ServerSlaveConnectionMaster{..})
+    }
+    pub fn add_reply(&mut self, reply: Reply) {
+        // master does not expect any replies to the commands that it replicates
+        if self.is_from_master() {
+            return;
+        }
+        encode_reply(&reply, self.protocol, &mut self.output);
+    }
+    pub async fn flush(&mut self) -> Option<()> {
+        if self.output.is_empty() {
+            return Some(());
+        }
+        let output = std::mem::take(&mut self.output);
+        write_raw(&mut self.stream, output).await
+    }
+    /// Replies are only written when the client has sent all the commands that it had, to save on syscalls
+    async fn flush_if_no_pending_commands(&mut self) -> Option<()> {
+        if self.stream.buffer().is_empty() {
+            self.flush().await?;
+        }
+        Some(())
+    }
+    /// RESP2 clients can't tell the replies from the messages, so they can only run a few commands while subscribed
+    pub fn is_in_subscriber_mode(&self) -> bool {
+        self.protocol == Protocol::Resp2 && self.is_subscribed()
+    }
+    pub fn is_subscribed(&self) -> bool {
+        self.subscriber.as_ref().is_some_and(|x| x.is_subscribed())
+    }
+    pub fn unsubscribe_all(&mut self) {
+        if let Some(subscriber) = &mut self.subscriber {
+            self.server.pubsub.write().expect("got poisoned lock")
+                .unsubscribe_all(subscriber);
+        }
     }
     pub fn get_transaction_mut(&mut self) -> Option<&mut Transaction> {
         match &mut self.kind {
@@ -30,24 +91,75 @@ impl Connection {
     fn replicated_offset_ref(&self) -> Option<&Cell<usize>> {
         match &self.kind {
             ConnectionKind::ServerMasterConnectionExternal { replicated_offset, .. } => Some(replicated_offset),
-            ConnectionKind::ServerSlaveConnectionMaster { replicated_offset } => Some(replicated_offset),
             _ => None,
         }
     }
     pub fn replicate(&self, command: Command) {
+        self.replicate_all(vec![command])
+    }
+    /// Several commands are wrapped in MULTI / EXEC, so that replicas apply them atomically too
+    pub fn replicate_all(&self, commands: Vec<Command>) {
+        if let ConnectionKind::ServerSlaveConnectionMaster{forwarded} = &self.kind {
+            // the stream from master is forwarded as is, so that our offsets match master's
+            if let Some(data) = forwarded.take() {
+                self.server.replication.write().expect("got a poisoned lock, can't handle it")
+                    .send_raw(data);
+            }
+            return;
+        }
+        if commands.is_empty() {
+            return;
+        }
         let offset_store = self.replicated_offset_ref()
-            .expect(format!("we should not send anything to replication from connection kind {:?}", self.kind).as_str());
-        let offset_value = self.server.replication.write().expect("got a poisoned lock, can't handle it")
-            .send(command);
+            .unwrap_or_else(|| panic!("we should not send anything to replication from connection kind {:?}", self.kind));
+        let is_transaction = commands.len() > 1;
+        let mut replication = self.server.replication.write().expect("got a poisoned lock, can't handle it");
+        let mut offset_value = replication.get_offset();
+        if is_transaction {
+            replication.send(Command::new(vec![b"MULTI".to_vec()]).expect("hardcoded command should be valid"));
+        }
+        for command in commands {
+            offset_value = replication.send(command);
+        }
+        if is_transaction {
+            offset_value = replication.send(Command::new(vec![b"EXEC".to_vec()]).expect("hardcoded command should be valid"));
+        }
         offset_store.set(offset_value)
     }
+    pub fn unwatch_all(&mut self) {
+        let server = Arc::clone(&self.server);
+        if let Some(transaction) = self.get_transaction_mut() {
+            for (key, _) in transaction.watched.drain(..) {
+                server.storage.unwatch(&key);
+            }
+        }
+    }
+    /// Role of the server can be changed at any time, and external connections should follow it
+    pub fn update_kind(&mut self) {
+        let is_slave = self.server.is_slave();
+        match self.kind {
+            ConnectionKind::ServerMasterConnectionExternal { .. } if is_slave => {
+                self.unwatch_all();
+                self.kind = ConnectionKind::ServerSlaveConnectionExternal;
+            },
+            ConnectionKind::ServerSlaveConnectionExternal if !is_slave => {
+                self.kind = new_master_connection_external();
+            },
+            _ => {},
+        }
+    }
     pub fn convert_to_slave(mut self) -> Self {
         assert!(
             self.is_external(),
             "trying to convert wrong connection to slave {:?}", self.kind
         );
+        let ip = match self.stream.get_ref().peer_addr() {
+            Ok(x) => x.ip().to_string(),
+            Err(_) => "?".to_string(),
+        };
+        let port = self.listening_port.unwrap_or(0);
         let slave_id = self.server.slave_state.write().expect("got poisoned lock")
-            .connect();
+            .connect(ip, port);
         self.kind = ConnectionKind::ServerMasterConnectionSlave{slave_id};
         self
     }
@@ -73,7 +185,7 @@ impl Connection {
     }
     pub fn get_replicated_offset(&self) -> usize {
         let offset_store = self.replicated_offset_ref()
-            .expect(format!("can't check acknowledged replicas for connection kind {:?}", self.kind).as_str());
+            .unwrap_or_else(|| panic!("can't check acknowledged replicas for connection kind {:?}", self.kind));
         offset_store.get()
     }
     pub fn check_acknowledged_replicas(&self, offset: usize) -> (usize, usize) {
@@ -83,21 +195,22 @@ impl Connection {
 }
 impl Drop for Connection {
     fn drop(&mut self) {
-        match self.kind {
-            ConnectionKind::ServerMasterConnectionSlave { slave_id } => {
-                self.server.slave_state.write().expect("got poisoned lock")
-                    .disconnect(slave_id);
-            },
-            _ => {},
+        self.unwatch_all();
+        self.unsubscribe_all();
+        if let ConnectionKind::ServerMasterConnectionSlave { slave_id } = self.kind {
+            self.server.slave_state.write().expect("got poisoned lock")
+                .disconnect(slave_id);
         };
     }
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub(crate) enum ConnectionKind {
     ServerMasterConnectionExternal{replicated_offset: Cell<usize>, transaction: Transaction},
     ServerMasterConnectionSlave{slave_id: usize},
-    ServerSlaveConnectionMaster{replicated_offset: Cell<usize>},
+    /// `forwarded` holds the exact bytes of the command from master that is being applied, or of the whole MULTI / EXEC block
+    ServerSlaveConnectionMaster{forwarded: RefCell<Option<Vec<u8>>>},
     ServerSlaveConnectionExternal,
 }
 
@@ -111,77 +224,138 @@ pub(crate) enum ConnectionKind {
         Because that might mean that some data was lost.
 
     Replication:
-        We are using broadcast channels for replication, because we want each slave to have their own queue of messages,
-        so that slow slaves do not affect fast slaves;
-        For master server, normal connections should have a transmitter side of the channel, while slave connections should have a receiver side of the channel.
+        Each slave has its own output buffer (see output_buffer.rs), so that slow slaves do not affect fast slaves.
+        Replication keeps a ReplicaSender for every slave and puts the replicated data into all of them,
+        while the slave connection writes out whatever arrives at its ReplicaReceiver.
+        A slave whose buffer goes over client-output-buffer-limit is disconnected, and can continue from the backlog after reconnecting.
         We can't detect that a connection is a slave until the handshake is completed,
-        so we start as a normal channel with a transmitter, and then convert to a slave with a receiver.
+        so it starts as a normal connection, and gets its receiver once PSYNC succeeds.
  */
-pub(crate) async fn handle_external(stream: TcpStream, server: Arc<Server>) -> Option<(Connection, Receiver<Command>)> {
-    let kind = if server.is_slave {
-        ConnectionKind::ServerSlaveConnectionExternal
-    } else {
-        ConnectionKind::ServerMasterConnectionExternal { replicated_offset: Default::default(), transaction: Default::default() }
-    };
+fn new_master_connection_external() -> ConnectionKind {
+    ConnectionKind::ServerMasterConnectionExternal { replicated_offset: Default::default(), transaction: Default::default() }
+}
+
+pub(crate) async fn handle_external(stream: TcpStream, server: Arc<Server>) -> Option<(Connection, ReplicaReceiver)> {
     let mut connection = Connection {
+        id: server.new_client_id(),
         stream: BufReader::new(stream),
         server,
-        kind,
+        kind: ConnectionKind::ServerSlaveConnectionExternal,
+        listening_port: None,
+        supports_eof: false,
+        subscriber: None,
+        protocol: Protocol::Resp2,
+        name: None,
+        output: Vec::new(),
     };
     loop {
-        let command_raw = read_command(&mut connection.stream).await?;
-        let Some(command) = Command::new(command_raw) else {
-            // todo: return error replies instead of just logging errors
-            continue;
-        };
-        if command.name == "PSYNC" {
+        connection.flush_if_no_pending_commands().await?;
+        wait_for_command(&mut connection).await?;
+        let command = read_client_command(&mut connection).await?;
+        connection.update_kind();
+        let is_psync = connection.lookup_command(&command.name).is_some_and(|x| x.name == "PSYNC");
+        // inside MULTI, it goes through handle_command, which rejects it
+        let is_in_transaction = connection.get_transaction_mut().is_some_and(|x| x.started);
+        if is_psync && !is_in_transaction {
+            // the replies have to be sent before the replication stream starts
+            connection.flush().await?;
             let res = psync(&mut connection, command).await;
             match res {
                 Ok(rx) => return Some((connection, rx)),
-                Err(err) => handle_err(err, &mut connection.stream).await?,
+                Err(err) => handle_err(err, &mut connection).await?,
             }
         } else {
             let res = handle_command(&mut connection, command).await;
             match res {
                 Ok(_) => {},
-                Err(err) => handle_err(err, &mut connection.stream).await?,
+                Err(err) => handle_err(err, &mut connection).await?,
             }
         }
     };
 }
 
-async fn handle_err(err: HandleError, stream: &mut (impl AsyncWriteExt + Unpin)) -> Option<()> {
+/// Delivers pub/sub messages to a subscribed connection, until the next command starts to arrive
+async fn wait_for_command(connection: &mut Connection) -> Option<()> {
+    loop {
+        let Some(subscriber) = &mut connection.subscriber else {
+            return Some(());
+        };
+        select! {
+            res = connection.stream.fill_buf() => {
+                return match res {
+                    Ok(buf) if !buf.is_empty() => Some(()),
+                    _ => None,
+                };
+            },
+            message = subscriber.receiver.recv() => {
+                let Ok(message) = message else {
+                    eprintln!("a subscriber is too slow to read the messages, disconnecting it");
+                    return None;
+                };
+                connection.add_reply(message.to_reply());
+                connection.flush().await?;
+            },
+        }
+    }
+}
+
+/// Protocol errors are reported to the client before the connection is closed, because we can't tell where the next command starts.
+async fn read_client_command(connection: &mut Connection) -> Option<Command> {
+    let res = read_command(&mut connection.stream, connection.server.protocol_limits).await;
+    match res {
+        Ok(command_raw) => Command::new(command_raw),
+        Err(ReadError::Closed) => None,
+        Err(ReadError::Protocol(message)) => {
+            eprintln!("protocol error from client: {message}");
+            connection.add_reply(Reply::Error(format!("ERR Protocol error: {message}")));
+            connection.flush().await?;
+            None
+        },
+    }
+}
+
+async fn handle_err(err: HandleError, connection: &mut Connection) -> Option<()> {
     match err {
         HandleError::InvalidArgs(err) => {
-            write_simple_error(stream, err.get_message()).await?;
+            connection.add_reply(Reply::Error(err.get_message().to_string()));
             Some(())
         },
         HandleError::ResponseFailed => None,
+        HandleError::Quit => {
+            connection.flush().await?;
+            None
+        },
     }
 }
 
-pub(crate) async fn handle_slave(connection: Connection, mut repl_receiver: Receiver<Command>) -> Option<()> {
+pub(crate) async fn handle_slave(connection: Connection, mut repl_receiver: ReplicaReceiver) -> Option<()> {
     let mut connection = connection.convert_to_slave();
     loop {
         select! {
-            command = read_command(&mut connection.stream) => {
-                let command_raw = command?;
-                if let Some(command) = Command::new(command_raw) {
-                    handle_command_ignore_invalid(&mut connection, command).await?;
-                };
+            // reading a command can not be interrupted half way, so only the arrival of its data is awaited here
+            res = connection.stream.fill_buf() => {
+                match res {
+                    Ok(buf) if !buf.is_empty() => {},
+                    _ => return None,
+                }
+                let command = read_client_command(&mut connection).await?;
+                handle_command_ignore_invalid(&mut connection, command).await?;
+                connection.flush_if_no_pending_commands().await?;
             },
-            replicated_command = (&mut repl_receiver).recv() => {
+            replicated_command = repl_receiver.recv() => {
                 match replicated_command {
-                    Ok(command) => {
-                        write_command(&mut connection.stream, command).await?;
+                    Ok(data) => {
+                        write_raw(&mut connection.stream, data).await?;
                     },
-                    Err(RecvError::Lagged(_)) => {
-                        // we have dropped some messages that should have been replicated, can't continue
+                    Err(OutputRecvError::Overflowed) => {
+                        // the replica can reconnect and continue from the backlog
                         eprintln!("a slave connection has lagged too much");
                         return None;
                     }
-                    Err(RecvError::Closed) => {
-                        unreachable!("main loop should always have a copy of a transmitter");
+                    Err(OutputRecvError::Closed) => {
+                        // replication history has changed, the replica has to reconnect and sync again
+                        eprintln!("replication stream was reset, disconnecting a slave");
+                        return None;
                     }
                 }
             },
@@ -191,24 +365,121 @@ pub(crate) async fn handle_slave(connection: Connection, mut repl_receiver: Rece
 
 pub(crate) async fn handle_master(stream: BufReader<TcpStream>, server: Arc<Server>) -> Option<()> {
     let mut connection = Connection {
+        id: server.new_client_id(),
         stream,
         server,
-        kind: ConnectionKind::ServerSlaveConnectionMaster{ replicated_offset: Default::default() },
+        kind: ConnectionKind::ServerSlaveConnectionMaster{forwarded: RefCell::new(None)},
+        listening_port: None,
+        supports_eof: false,
+        subscriber: None,
+        protocol: Protocol::Resp2,
+        name: None,
+        output: Vec::new(),
     };
+    // commands of a transaction from master are applied together, once EXEC arrives
+    let mut transaction: Option<Vec<Command>> = None;
+    let mut ack_interval = interval(ACK_INTERVAL);
+    ack_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
     loop {
-        let command_raw = read_command(&mut connection.stream).await?;
+        /*
+        Reading a command can't be interrupted half way without losing data,
+        so we only wait for the data to arrive here, and read the command itself afterwards.
+         */
+        select! {
+            res = connection.stream.fill_buf() => {
+                match res {
+                    Ok(buf) if !buf.is_empty() => {},
+                    _ => return None,
+                }
+            },
+            _ = ack_interval.tick() => {
+                // lets master know how far behind we are, even when it does not ask
+                write_ack(&mut connection).await?;
+                continue;
+            },
+        }
+        let res = read_command_with_bytes(&mut connection.stream, connection.server.protocol_limits).await;
+        let (command_raw, bytes) = match res {
+            Ok(x) => x,
+            Err(ReadError::Closed) => return None,
+            Err(ReadError::Protocol(message)) => {
+                eprintln!("protocol error from master: {message}");
+                return None;
+            },
+        };
         let Some(command) = Command::new(command_raw) else {
             // if we are unable to process master's command, we can't acknowledge that we've consumed the offset
             eprintln!("got a weird command from master, can't process it, shutting down the connection");
-            continue;
+            return None;
+        };
+        /*
+        The bytes are forwarded to our own replicas under the same storage lock that applies the command,
+        otherwise a replica doing a full resync in between could get the command both in the snapshot and in the stream.
+        Commands that don't touch the storage are forwarded after they are handled.
+        MULTI and the queued commands are kept until EXEC, so they are forwarded and counted in the offset together with it.
+         */
+        if let ConnectionKind::ServerSlaveConnectionMaster{forwarded} = &connection.kind {
+            forwarded.borrow_mut().get_or_insert_with(Vec::new).extend_from_slice(&bytes);
+        }
+        let res = match (command.name.as_str(), &mut transaction) {
+            ("MULTI", _) => {
+                transaction = Some(Vec::new());
+                continue;
+            },
+            ("EXEC", Some(_)) => {
+                let commands = transaction.take().unwrap_or_default();
+                exec_replicated_transaction(&mut connection, commands);
+                Ok(())
+            },
+            (_, Some(queue)) => {
+                queue.push(command);
+                continue;
+            },
+            _ => handle_command(&mut connection, command).await,
         };
-        let command_size = command.byte_size;
-        let res = handle_command(&mut connection, command).await;
         if res.is_err() {
-            // if we are unable to process master's command, we can't acknowledge that we've consumed the offset
+            // skipping the command would make our data and offset differ from master's, so we have to sync again
             eprintln!("failed to process master's command, shutting down the connection");
-            continue;
+            return None;
         }
-        connection.server.slave_read_offset.fetch_add(command_size, Ordering::AcqRel);
+        // this also counts the offset
+        connection.replicate_all(Vec::new());
     };
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+    use tokio::time::{sleep, timeout};
+    use crate::command_table::CommandNames;
+
+    fn get_offset(server: &Server) -> usize {
+        server.replication.read().expect("got poisoned lock").get_offset()
+    }
+
+    #[tokio::test]
+    async fn master_transaction_is_counted_after_exec() {
+        let server = Server::new_arc(Default::default(), 0, Default::default(), CommandNames::new(&[]));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut master = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        tokio::spawn(handle_master(BufReader::new(stream), Arc::clone(&server)));
+
+        let queued = b"*1\r\n$5\r\nMULTI\r\n*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n";
+        let exec = b"*1\r\n$4\r\nEXEC\r\n";
+        master.write_all(queued).await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(get_offset(&server), 0);
+        assert!(!server.storage.read_all().contains_key(b"k".as_slice()));
+
+        master.write_all(exec).await.unwrap();
+        let expected = queued.len() + exec.len();
+        timeout(Duration::from_secs(1), async {
+            while get_offset(&server) != expected {
+                sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("offset should include the whole transaction after EXEC");
+        assert!(server.storage.read_all().contains_key(b"k".as_slice()));
+    }
+}
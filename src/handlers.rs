@@ -1,61 +1,204 @@
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::mem;
 use std::str::FromStr;
-use std::sync::atomic::Ordering;
+use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::broadcast::Receiver;
-use tokio::time::sleep;
+use tokio::time::timeout;
 use crate::command::{Command, normalize_name};
+use crate::command_table::{all_commands, lookup_command, CommandFlag, CommandSpec};
 use crate::connection::{Connection, ConnectionKind};
+use crate::output_buffer::ReplicaReceiver;
+use crate::pubsub::{KeyspaceEventClass, SubscriptionKind};
+use crate::rdb::dump_chunks;
+use crate::server::{generate_replication_id, MasterLinkState};
 use crate::resp::*;
-use crate::storage::{ExpiryTs, now_ts, StorageItemSimple, StorageKey, StreamEntry, SimpleValue};
-use crate::transaction::QueuedCommand;
+use crate::storage::{append_to_stream, delete, get_expiry, get_simple, get_value_kind, increment, keys, set_expiry, set_string, ExpiryTs, now_ts, StorageInner, StorageItemSimple, StorageKey, StreamEntry, SimpleValue, WatchedVersion};
+use crate::transaction::{ExpireCondition, QueuedCommand, SetCondition, SetOptions};
 
-const EMPTY_RDB_FILE_HEX: &str = "524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2";
-
-const INVALID_ARGS_DEFAULT: HandleError = HandleError::InvalidArgs(ArgsError::Generic);
+const SYNTAX_ERROR: HandleError = HandleError::InvalidArgs(ArgsError::SyntaxError);
+/// the version of redis that we are compatible with, clients use it to detect the supported features
+const REDIS_VERSION: &str = "7.2.0";
 
 pub(crate) enum HandleError {
     InvalidArgs(ArgsError),
     ResponseFailed,
+    /// the client has asked to close the connection
+    Quit,
 }
 impl From<ArgsError> for HandleError {
     fn from(value: ArgsError) -> Self {
         Self::InvalidArgs(value)
     }
 }
+impl HandleError {
+    /// Errors of the commands inside EXEC are sent as a part of its reply
+    fn into_reply(self) -> Reply {
+        let message = match self {
+            HandleError::InvalidArgs(err) => err.get_message(),
+            HandleError::ResponseFailed | HandleError::Quit => ArgsError::SyntaxError.get_message(),
+        };
+        Reply::Error(message.to_string())
+    }
+}
 #[derive(Default)]
 pub(crate) enum ArgsError {
     #[default]
-    Generic,
+    SyntaxError,
+    NotAnInteger,
+    UnsupportedOption(String),
+    IncompatibleNxOption,
+    IncompatibleGtLtOptions,
+    /// contains the name of the command, as it is in the command table
+    InvalidExpireTime(&'static str),
+    UnknownCommand(Command),
+    /// contains the name of the command, as it is in the command table
+    WrongArity(&'static str),
+    WrongType,
+    ReadOnlyReplica,
     CanNotIncrementThisValue,
     ExecWithoutMulti,
     DiscardWithoutMulti,
+    NoReplicas,
+    WatchInsideMulti,
+    NestedMulti,
+    NotAllowedInMulti,
+    ExecAbort,
+    NotAllowedWhenSubscribed,
+    NoProto,
+    WrongPass,
+    InvalidClientName,
 }
 impl ArgsError {
-    pub(crate) fn get_message(&self) -> &'static str {
-        match self {
-            ArgsError::Generic => "Invalid args",
+    pub(crate) fn get_message(&self) -> Cow<'static, str> {
+        let message = match self {
+            ArgsError::SyntaxError => "ERR syntax error",
+            ArgsError::NotAnInteger => "ERR value is not an integer or out of range",
+            ArgsError::UnsupportedOption(option) => return format!("ERR Unsupported option {option}").into(),
+            ArgsError::IncompatibleNxOption => "ERR NX and XX, GT or LT options at the same time are not compatible",
+            ArgsError::IncompatibleGtLtOptions => "ERR GT and LT options at the same time are not compatible",
+            ArgsError::InvalidExpireTime(name) => {
+                return format!("ERR invalid expire time in '{}' command", name.to_lowercase()).into();
+            },
+            ArgsError::UnknownCommand(command) => return format_unknown_command(command).into(),
+            ArgsError::WrongArity(name) => {
+                return format!("ERR wrong number of arguments for '{}' command", name.to_lowercase()).into();
+            },
+            ArgsError::WrongType => "WRONGTYPE Operation against a key holding the wrong kind of value",
+            ArgsError::ReadOnlyReplica => "READONLY You can't write against a read only replica.",
             ArgsError::CanNotIncrementThisValue => "ERR value is not an integer or out of range",
             ArgsError::ExecWithoutMulti => "ERR EXEC without MULTI",
             ArgsError::DiscardWithoutMulti => "ERR DISCARD without MULTI",
+            ArgsError::NoReplicas => "NOREPLICAS Not enough good replicas to write.",
+            ArgsError::WatchInsideMulti => "ERR WATCH inside MULTI is not allowed",
+            ArgsError::NestedMulti => "ERR MULTI calls can not be nested",
+            ArgsError::NotAllowedInMulti => "ERR Command not allowed inside a transaction",
+            ArgsError::ExecAbort => "EXECABORT Transaction discarded because of previous errors.",
+            ArgsError::NotAllowedWhenSubscribed => "ERR only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+            ArgsError::NoProto => "NOPROTO sorry, this protocol version is not supported.",
+            ArgsError::WrongPass => "WRONGPASS invalid username-password pair or user is disabled.",
+            ArgsError::InvalidClientName => "ERR Client names cannot contain spaces, newlines or special characters.",
+        };
+        message.into()
+    }
+}
+
+/// Same as redis: the arguments are cut after 128 characters, and line breaks are replaced with spaces.
+fn format_unknown_command(command: &Command) -> String {
+    const MAX_ARGS_SIZE: usize = 128;
+    let name = String::from_utf8_lossy(&command.raw[0]);
+    let mut args = String::new();
+    for arg in command.get_args() {
+        if args.len() >= MAX_ARGS_SIZE {
+            break;
         }
+        let arg: String = String::from_utf8_lossy(arg).chars().take(MAX_ARGS_SIZE - args.len()).collect();
+        args.push_str(&format!("'{arg}' "));
     }
+    let name: String = name.chars().take(MAX_ARGS_SIZE).collect();
+    format!("ERR unknown command '{name}', with args beginning with: {args}")
+        .replace(['\r', '\n'], " ")
 }
 type HandleResult<T> = Result<T, HandleError>;
-type ExecResult<T> = Result<T, ArgsError>;
 
-pub(crate) async fn psync(connection: &mut Connection, command: Command) -> HandleResult<Receiver<Command>> {
+pub(crate) async fn psync(connection: &mut Connection, command: Command) -> HandleResult<ReplicaReceiver> {
     let args = command.get_args();
-    let args = split_and_assert_value(args, b"?")?;
-    let _ = split_and_assert_value(args, b"-1")?;
-    write_simple_string(&mut connection.stream, format!("FULLRESYNC {} 0", connection.server.replication_id)).await
+    let (replication_id, args) = split_arg(args)?;
+    let (offset, _) = split_and_parse_value::<i64>(args)?;
+    if let Some(repl_rx) = partial_resync(connection, replication_id, offset).await? {
+        return Ok(repl_rx);
+    }
+    full_resync(connection).await
+}
+
+async fn partial_resync(connection: &mut Connection, replication_id: &[u8], offset: i64) -> HandleResult<Option<ReplicaReceiver>> {
+    // replicas ask for the offset of the next byte that they need, and we count from 0
+    let Ok(offset) = usize::try_from(offset - 1) else {
+        return Ok(None);
+    };
+    let continued = connection.server.replication.write().expect("got poisoned lock")
+        .subscribe_from(replication_id, offset);
+    let Some((replication_id, data, repl_rx)) = continued else {
+        return Ok(None);
+    };
+    write_simple_string(&mut connection.stream, format!("CONTINUE {replication_id}")).await
+        .ok_or(HandleError::ResponseFailed)?;
+    write_raw(&mut connection.stream, data).await
+        .ok_or(HandleError::ResponseFailed)?;
+    Ok(Some(repl_rx))
+}
+
+async fn full_resync(connection: &mut Connection) -> HandleResult<ReplicaReceiver> {
+    if connection.server.repl_diskless_sync && connection.supports_eof {
+        return full_resync_diskless(connection).await;
+    }
+    let (snapshot, repl_rx, replication_id, offset) = snapshot_for_replica(connection);
+    // the size has to be sent first, so the whole file is produced before sending it
+    let chunks: Vec<_> = dump_chunks(&snapshot).ok_or(HandleError::ResponseFailed)?.collect();
+    drop(snapshot);
+    let size = chunks.iter().map(|x| x.len()).sum();
+    write_simple_string(&mut connection.stream, format!("FULLRESYNC {replication_id} {offset}")).await
         .ok_or(HandleError::ResponseFailed)?;
-    let file_contents = hex::decode(EMPTY_RDB_FILE_HEX).expect("hardcoded db file should be decodable");
-    write_binary_string(&mut connection.stream, file_contents, false).await
+    write_binary_string_start(&mut connection.stream, size).await
         .ok_or(HandleError::ResponseFailed)?;
-    let repl_rx = connection.server.replication.read().expect("got poisoned lock").subscribe();
-    return Ok(repl_rx);
+    for chunk in chunks {
+        write_raw(&mut connection.stream, chunk).await
+            .ok_or(HandleError::ResponseFailed)?;
+    }
+    Ok(repl_rx)
+}
+
+async fn full_resync_diskless(connection: &mut Connection) -> HandleResult<ReplicaReceiver> {
+    /*
+    Same as full_resync, but the file is written to the replica while it's being produced,
+    so its size is not known in advance, and the end is marked by a random string instead.
+     */
+    let (snapshot, repl_rx, replication_id, offset) = snapshot_for_replica(connection);
+    let chunks = dump_chunks(&snapshot).ok_or(HandleError::ResponseFailed)?;
+    write_simple_string(&mut connection.stream, format!("FULLRESYNC {replication_id} {offset}")).await
+        .ok_or(HandleError::ResponseFailed)?;
+    let mark = generate_replication_id();
+    write_eof_marked_string_start(&mut connection.stream, &mark).await
+        .ok_or(HandleError::ResponseFailed)?;
+    for chunk in chunks {
+        write_raw(&mut connection.stream, chunk).await
+            .ok_or(HandleError::ResponseFailed)?;
+    }
+    write_raw(&mut connection.stream, mark).await
+        .ok_or(HandleError::ResponseFailed)?;
+    Ok(repl_rx)
+}
+
+/*
+The snapshot has to match the replication offset exactly,
+so it's taken under the storage lock, and we subscribe to replication before releasing it.
+Writes hold the storage lock while replicating, so nothing can sneak in between.
+Only the map is copied, the values are shared with the storage until somebody modifies them.
+ */
+fn snapshot_for_replica(connection: &Connection) -> (StorageInner, ReplicaReceiver, String, usize) {
+    let guard = connection.server.storage.read_all();
+    let snapshot = guard.clone();
+    let mut replication = connection.server.replication.write().expect("got poisoned lock");
+    (snapshot, replication.subscribe(), replication.get_id().to_string(), replication.get_offset())
 }
 
 pub(crate) async fn handle_command_ignore_invalid(connection: &mut Connection, command: Command) -> Option<()> {
@@ -63,205 +206,751 @@ pub(crate) async fn handle_command_ignore_invalid(connection: &mut Connection, c
     match res {
         Ok(x) => Some(x),
         Err(HandleError::InvalidArgs(_)) => Some(()),
-        Err(HandleError::ResponseFailed) => None,
+        Err(HandleError::ResponseFailed | HandleError::Quit) => None,
     }
 }
 
-pub(crate) async fn handle_command(connection: &mut Connection, command: Command) -> HandleResult<()> {
-    match command.name.as_str() {
-        "PING" => ping(connection).await,
-        "ECHO" => echo(connection, command).await,
-        "GET" => get(connection, command).await,
-        "SET" => set(connection, command).await,
-        "INFO" => info(connection, command).await,
+pub(crate) async fn handle_command(connection: &mut Connection, mut command: Command) -> HandleResult<()> {
+    let spec = match check_command(connection, &command) {
+        Ok(x) => x,
+        Err(err) => {
+            // the whole transaction will be rejected by EXEC
+            if let Some(transaction) = connection.get_transaction_mut().filter(|x| x.started) {
+                transaction.has_errors = true;
+            }
+            return Err(err);
+        },
+    };
+    // everything after this point, including replication and EXEC, works with the canonical names
+    if command.name != spec.name {
+        command.set_name(spec.name);
+    }
+    let is_allowed_when_subscribed = [
+        "SUBSCRIBE", "UNSUBSCRIBE", "PSUBSCRIBE", "PUNSUBSCRIBE", "SSUBSCRIBE", "SUNSUBSCRIBE",
+        "PING", "QUIT", "RESET",
+    ].contains(&command.name.as_str());
+    if connection.is_in_subscriber_mode() && !is_allowed_when_subscribed {
+        return Err(ArgsError::NotAllowedWhenSubscribed.into());
+    }
+    let is_queued = connection.get_transaction_mut().is_some_and(|x| x.started)
+        && !["MULTI", "EXEC", "DISCARD", "WATCH", "QUIT", "RESET"].contains(&command.name.as_str());
+    if is_queued {
+        return queue_command(connection, command, spec).await;
+    }
+    match spec.name {
+        name if is_data_command(name) => data_command(connection, command).await,
+        "PING" | "ECHO" | "INFO" | "CONFIG" | "ROLE" | "COMMAND" => simple_command(connection, command).await,
+        "PUBLISH" | "SPUBLISH" | "PUBSUB" => simple_command(connection, command).await,
+        "SUBSCRIBE" | "PSUBSCRIBE" | "SSUBSCRIBE" => connection_command(connection, command).await,
+        "UNSUBSCRIBE" | "PUNSUBSCRIBE" | "SUNSUBSCRIBE" => connection_command(connection, command).await,
+        "HELLO" | "REPLICAOF" | "SLAVEOF" => connection_command(connection, command).await,
+        "QUIT" => quit(connection).await,
+        "RESET" => reset(connection).await,
         "REPLCONF" => repl_conf(connection, command).await,
         "WAIT" => wait(connection, command).await,
-        "CONFIG" => config(connection, command).await,
-        "KEYS" => keys(connection, command).await,
-        "TYPE" => handle_type(connection, command).await,
-        "XADD" => xadd(connection, command).await,
-        "INCR" => incr(connection, command).await,
         "MULTI" => multi(connection).await,
         "EXEC" => exec(connection).await,
         "DISCARD" => discard(connection).await,
+        "WATCH" => watch(connection, command).await,
+        "UNWATCH" => unwatch(connection).await,
         _ => {
-            eprintln!("received unknown command {} {:?}", command.name, command.raw);
-            Err(INVALID_ARGS_DEFAULT)
+            eprintln!("command {} has no handler", command.name);
+            Err(SYNTAX_ERROR)
         },
     }
 }
 
-async fn ping(connection: &mut Connection) -> HandleResult<()> {
-    if matches!(connection.kind, ConnectionKind::ServerSlaveConnectionMaster{..}) {
-        Ok(())
-    } else {
-        write_simple_string(&mut connection.stream, "PONG").await
-            .ok_or(HandleError::ResponseFailed)
+/// Finds the command in the command table, and checks that it can be called with these arguments on this connection
+fn check_command(connection: &Connection, command: &Command) -> HandleResult<&'static CommandSpec> {
+    let Some(spec) = connection.lookup_command(&command.name) else {
+        eprintln!("received unknown command {} {:?}", command.name, command.raw);
+        return Err(ArgsError::UnknownCommand(command.clone()).into());
+    };
+    if !spec.check_arity(command.raw.len()) {
+        return Err(ArgsError::WrongArity(spec.name).into());
     }
+    if spec.has_flag(CommandFlag::Write) {
+        check_can_write(connection)?;
+    }
+    Ok(spec)
 }
 
-async fn echo(connection: &mut Connection, command: Command) -> HandleResult<()> {
-    let (value, _) = split_arg(command.get_args())?;
-    write_binary_string(&mut connection.stream, value, true).await
-        .ok_or(HandleError::ResponseFailed)
+/// Commands after MULTI are only validated, and they are executed later by EXEC
+async fn queue_command(connection: &mut Connection, command: Command, spec: &CommandSpec) -> HandleResult<()> {
+    let res = validate_queued(&command, spec);
+    let Some(transaction) = connection.get_transaction_mut() else {
+        return Err(SYNTAX_ERROR);
+    };
+    if let Err(err) = res {
+        // the whole transaction will be rejected by EXEC
+        transaction.has_errors = true;
+        return Err(err);
+    }
+    transaction.queue.push(command);
+    connection.add_reply(Reply::SimpleString("QUEUED".to_string()));
+    Ok(())
 }
 
-async fn get(connection: &mut Connection, command: Command) -> HandleResult<()> {
-    let (key, _) = split_arg(command.get_args())?;
-    if let Some(transaction) = connection.get_transaction_mut() {
-        if transaction.started {
-            let key = key.clone();
-            transaction.queue.push(QueuedCommand::Get{key});
-            return write_simple_string(&mut connection.stream, "QUEUED").await
-                .ok_or(HandleError::ResponseFailed);
-        }
+fn validate_queued(command: &Command, spec: &CommandSpec) -> HandleResult<()> {
+    if spec.has_flag(CommandFlag::NoMulti) {
+        return Err(ArgsError::NotAllowedInMulti.into());
     }
-    exec_get(connection, key).await
+    if is_data_command(spec.name) {
+        // the command is parsed again by EXEC, so that relative expiry is counted from the moment of execution
+        parse_queued(command.clone())?;
+    }
+    Ok(())
 }
 
-async fn exec_get(connection: &mut Connection, key: &StorageKey) -> HandleResult<()> {
-    let result = connection.server.storage.get_simple(key);
-    let stream = &mut connection.stream;
-    match result {
-        None => write_null(stream).await,
-        Some(SimpleValue::String(data)) => write_binary_string(stream, data, true).await,
-        Some(SimpleValue::Int(data)) => write_binary_string(stream, data.to_string(), true).await,
-    }.ok_or(HandleError::ResponseFailed)
+/// Commands that don't touch the storage, and can reply right away
+async fn simple_command(connection: &mut Connection, command: Command) -> HandleResult<()> {
+    let mut replicated = Vec::new();
+    let reply = exec_simple(connection, &command, &mut replicated)?;
+    connection.replicate_all(replicated);
+    connection.add_reply(reply);
+    Ok(())
 }
 
-async fn set(connection: &mut Connection, command: Command) -> HandleResult<()> {
-    if !connection.can_replicate() {
-        eprintln!("set command was called via readonly connection");
-        return Err(INVALID_ARGS_DEFAULT);
+/// Commands that have to be replicated are added to `replicated`
+fn exec_simple(connection: &Connection, command: &Command, replicated: &mut Vec<Command>) -> HandleResult<Reply> {
+    let args = command.get_args();
+    match command.name.as_str() {
+        "PING" => Ok(ping(connection, args)),
+        "ECHO" => {
+            let (value, _) = split_arg(args)?;
+            Ok(Reply::BinaryString(value.clone()))
+        },
+        "INFO" => info(connection, args),
+        "CONFIG" => config(connection, args),
+        "ROLE" => Ok(role(connection)),
+        "COMMAND" => command_introspection(connection, args),
+        "PUBLISH" => publish(connection, args),
+        "SPUBLISH" => {
+            let reply = shard_publish(connection, args)?;
+            // replicas of a replica get the command from the stream of its master
+            if connection.can_write() {
+                replicated.push(command.clone());
+            }
+            Ok(reply)
+        },
+        "PUBSUB" => pubsub(connection, args),
+        // watched keys are forgotten after EXEC anyway
+        "UNWATCH" => Ok(Reply::SimpleString("OK".to_string())),
+        _ => {
+            eprintln!("command {} can't be executed here", command.name);
+            Err(SYNTAX_ERROR)
+        },
     }
-    let (key, item) = parse_set_args(command.get_args())?;
-    if let Some(transaction) = connection.get_transaction_mut() {
-        if transaction.started {
-            transaction.queue.push(QueuedCommand::Set{key, item, command});
-            return write_simple_string(&mut connection.stream, "QUEUED").await
-                .ok_or(HandleError::ResponseFailed);
-        }
+}
+
+/// Commands that change the state of the connection, they can be executed by EXEC too
+async fn connection_command(connection: &mut Connection, command: Command) -> HandleResult<()> {
+    let replies = exec_connection(connection, &command)?;
+    for reply in replies {
+        connection.add_reply(reply);
     }
-    do_set(connection, key, item, command);
-    if connection.server.is_slave {
-        Ok(())
-    } else {
-        write_simple_string(&mut connection.stream, "OK").await
-            .ok_or(HandleError::ResponseFailed)
+    Ok(())
+}
+
+/// Subscriptions reply once for each channel, so there can be more than one reply
+fn exec_connection(connection: &mut Connection, command: &Command) -> HandleResult<Vec<Reply>> {
+    match command.name.as_str() {
+        "SUBSCRIBE" => subscribe(connection, command, SubscriptionKind::Channel),
+        "PSUBSCRIBE" => subscribe(connection, command, SubscriptionKind::Pattern),
+        "SSUBSCRIBE" => subscribe(connection, command, SubscriptionKind::ShardChannel),
+        "UNSUBSCRIBE" => Ok(unsubscribe(connection, command, SubscriptionKind::Channel)),
+        "PUNSUBSCRIBE" => Ok(unsubscribe(connection, command, SubscriptionKind::Pattern)),
+        "SUNSUBSCRIBE" => Ok(unsubscribe(connection, command, SubscriptionKind::ShardChannel)),
+        "HELLO" => Ok(vec![hello(connection, command)?]),
+        "REPLICAOF" | "SLAVEOF" => Ok(vec![replicaof(connection, command)?]),
+        // these ones are handled separately outside of a transaction
+        "REPLCONF" => Ok(vec![repl_conf_option(connection, command.get_args())?]),
+        "WAIT" => Ok(vec![wait_without_blocking(connection, command.get_args())?]),
+        _ => {
+            eprintln!("command {} can't be executed here", command.name);
+            Err(SYNTAX_ERROR)
+        },
     }
 }
 
-fn parse_set_args(args: &[Vec<u8>]) -> HandleResult<(StorageKey, StorageItemSimple)> {
-    let (key, args) = split_arg(args)?;
-    let (value, args) = split_arg(args)?;
-    let expiry = parse_expiry(args)?;
-    let item = StorageItemSimple::from_data(value.clone(), expiry);
-    Ok((key.clone(), item))
+fn is_connection_command(name: &str) -> bool {
+    [
+        "SUBSCRIBE", "PSUBSCRIBE", "SSUBSCRIBE", "UNSUBSCRIBE", "PUNSUBSCRIBE", "SUNSUBSCRIBE",
+        "HELLO", "REPLICAOF", "SLAVEOF", "REPLCONF", "WAIT",
+    ].contains(&name)
 }
 
-fn parse_expiry(args: &[Vec<u8>]) -> HandleResult<Option<ExpiryTs>> {
-    if args.len() == 0 {
-        return Ok(None);
+fn ping(connection: &Connection, args: &[Vec<u8>]) -> Reply {
+    let message = args.first();
+    if connection.is_in_subscriber_mode() {
+        // subscribed connections can't tell a reply from a message, unless it's an array
+        return Reply::Array(vec![
+            Reply::BinaryString(b"pong".to_vec()),
+            Reply::BinaryString(message.cloned().unwrap_or_default()),
+        ]);
     }
-    let expiry_index = args.iter().position(|x| x.to_ascii_lowercase() == b"px");
-    let Some(expiry_index) = expiry_index else {
-        return Ok(None);
-    };
-    let expiry_value = match args.get(expiry_index + 1) {
-        Some(x) => x,
-        None => {
-            eprintln!("No value found for the expiry param");
-            return Err(INVALID_ARGS_DEFAULT);
-        }
+    match message {
+        Some(message) => Reply::BinaryString(message.clone()),
+        None => Reply::SimpleString("PONG".to_string()),
+    }
+}
+
+/// Commands that work with the storage, they are applied under the storage lock
+fn is_data_command(name: &str) -> bool {
+    [
+        "GET", "SET", "XADD", "INCR", "DEL", "KEYS", "TYPE",
+        "EXPIRE", "PEXPIRE", "EXPIREAT", "PEXPIREAT",
+    ].contains(&name)
+}
+
+async fn data_command(connection: &mut Connection, command: Command) -> HandleResult<()> {
+    let queued = parse_queued(command)?;
+    let reply = exec_single(connection, queued);
+    connection.add_reply(reply);
+    Ok(())
+}
+
+fn parse_queued(command: Command) -> HandleResult<QueuedCommand> {
+    let args = command.get_args();
+    let res = match command.name.as_str() {
+        "GET" => {
+            let (key, _) = split_arg(args)?;
+            QueuedCommand::Get{key: key.clone()}
+        },
+        "SET" => {
+            let (key, item, options, command) = parse_set_args(args)?;
+            QueuedCommand::Set{key, item, options, command}
+        },
+        "XADD" => {
+            let (key, item) = parse_xadd_args(args)?;
+            QueuedCommand::Xadd{key, item, command}
+        },
+        "INCR" => {
+            let (key, _) = split_arg(args)?;
+            QueuedCommand::Incr{key: key.clone(), command}
+        },
+        "DEL" => {
+            if args.is_empty() {
+                eprintln!("del command needs at least one key");
+                return Err(SYNTAX_ERROR);
+            }
+            QueuedCommand::Del{keys: args.to_vec(), command}
+        },
+        "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" => {
+            let (key, args) = split_arg(args)?;
+            let (value, args) = split_and_parse_value::<i64>(args)?;
+            let condition = parse_expire_condition(args)?;
+            let expires_at = to_expiry_ts(&command.name, value)
+                .ok_or_else(|| ArgsError::InvalidExpireTime(lookup_command(&command.name).map_or("EXPIRE", |x| x.name)))?;
+            QueuedCommand::Expire{key: key.clone(), expires_at, condition}
+        },
+        "KEYS" => {
+            split_and_assert_value(args, b"*")?;
+            QueuedCommand::Keys
+        },
+        "TYPE" => {
+            let (key, _) = split_arg(args)?;
+            QueuedCommand::Type{key: key.clone()}
+        },
+        _ => {
+            eprintln!("command {} can't be queued", command.name);
+            return Err(SYNTAX_ERROR);
+        },
     };
-    let expiry_value = parse_value::<u128>(expiry_value)?;
-    let expires_at = now_ts() + expiry_value;
-    Ok(Some(expires_at))
+    Ok(res)
 }
 
-fn do_set(connection: &mut Connection, key: StorageKey, item: StorageItemSimple, command: Command) {
+fn exec_single(connection: &mut Connection, command: QueuedCommand) -> Reply {
     /*
     We need to ensure that replicas have exactly the same state as master,
     so if there are concurrent updates to the same key, replicas need to receive them in the same order as they were applied in master,
     so sending commands to replicas should be done under the same lock as the updates.
      */
-    let guard = connection.server.storage.set_string(key, item);
-    connection.replicate(command);
-    drop(guard); // guard is unused, it just needs to exist until the end of scope
-}
-
-async fn info(connection: &mut Connection, command: Command) -> HandleResult<()> {
-    for section in command.get_args() {
-        match section.as_slice() {
-            b"replication" => info_replication(connection).await?,
-            b"SERVER" => {
-                write_binary_string(&mut connection.stream, "# Server\n", true).await
-                    .ok_or(HandleError::ResponseFailed)?
-            },
-            _ => eprintln!("Unknown section {:?}", std::str::from_utf8(&section)),
-        };
+    let mut guard = connection.server.storage.write_all();
+    let mut replicated = Vec::new();
+    let reply = exec_queued(connection, &mut guard, command, &mut replicated);
+    connection.replicate_all(replicated);
+    drop(guard);
+    reply
+}
+
+/// Applies the command to the locked storage, and adds the commands that have to be replicated to `replicated`.
+fn exec_queued(connection: &Connection, storage: &mut StorageInner, command: QueuedCommand, replicated: &mut Vec<Command>) -> Reply {
+    let server = &connection.server;
+    let mut expire_if_needed = |storage: &mut StorageInner, key: &StorageKey| {
+        if let Some(command) = server.expire_if_needed_in(storage, key) {
+            replicated.push(command);
+        }
+    };
+    let notify_if_new = |storage: &StorageInner, key: &StorageKey| {
+        if !storage.contains_key(key) {
+            server.notify_keyspace_event(KeyspaceEventClass::New, "new", key);
+        }
+    };
+    match command {
+        QueuedCommand::Get { key } => {
+            expire_if_needed(storage, &key);
+            if is_wrong_type(storage, &key, "string") {
+                return wrong_type_reply();
+            }
+            let value = get_simple(storage, &key);
+            if value.is_none() {
+                server.notify_keyspace_event(KeyspaceEventClass::KeyMiss, "keymiss", &key);
+            }
+            simple_value_reply(value)
+        },
+        QueuedCommand::Set { key, mut item, options, command } => {
+            expire_if_needed(storage, &key);
+            if options.get && is_wrong_type(storage, &key, "string") {
+                return wrong_type_reply();
+            }
+            let exists = storage.contains_key(&key);
+            let is_allowed = match options.condition {
+                None => true,
+                Some(SetCondition::IfMissing) => !exists,
+                Some(SetCondition::IfExists) => exists,
+            };
+            let reply = match (options.get, is_allowed) {
+                (true, _) => simple_value_reply(get_simple(storage, &key)),
+                (false, true) => Reply::SimpleString("OK".to_string()),
+                (false, false) => Reply::Null,
+            };
+            if !is_allowed {
+                return reply;
+            }
+            notify_if_new(storage, &key);
+            let has_expiry = item.expires_at.is_some();
+            if options.keep_ttl {
+                item.expires_at = get_expiry(storage, &key);
+            }
+            let is_volatile = item.expires_at.is_some();
+            set_string(storage, key.clone(), item);
+            server.storage.touch(&key);
+            if is_volatile {
+                server.storage.add_volatile(&key);
+            }
+            server.notify_keyspace_event(KeyspaceEventClass::String, "set", &key);
+            if has_expiry {
+                server.notify_keyspace_event(KeyspaceEventClass::Generic, "expire", &key);
+            }
+            replicated.push(command);
+            reply
+        },
+        QueuedCommand::Xadd { key, item, command } => {
+            expire_if_needed(storage, &key);
+            let id = item.id.clone();
+            let is_new = !storage.contains_key(&key);
+            if append_to_stream(storage, key.clone(), item).is_none() {
+                return wrong_type_reply();
+            }
+            if is_new {
+                server.notify_keyspace_event(KeyspaceEventClass::New, "new", &key);
+            }
+            server.storage.touch(&key);
+            server.notify_keyspace_event(KeyspaceEventClass::Stream, "xadd", &key);
+            replicated.push(command);
+            Reply::BinaryString(id)
+        },
+        QueuedCommand::Incr { key, command } => {
+            expire_if_needed(storage, &key);
+            if is_wrong_type(storage, &key, "string") {
+                return wrong_type_reply();
+            }
+            let is_new = !storage.contains_key(&key);
+            let Some(value) = increment(storage, key.clone()) else {
+                eprintln!("can't do incr when key is not an int");
+                return Reply::Error(ArgsError::CanNotIncrementThisValue.get_message().to_string());
+            };
+            if is_new {
+                server.notify_keyspace_event(KeyspaceEventClass::New, "new", &key);
+            }
+            server.storage.touch(&key);
+            server.notify_keyspace_event(KeyspaceEventClass::String, "incrby", &key);
+            replicated.push(command);
+            Reply::Int(value)
+        },
+        QueuedCommand::Del { keys, command } => {
+            // expired keys are deleted (and replicated) separately, so that they are not counted
+            for key in &keys {
+                expire_if_needed(storage, key);
+            }
+            // replicas don't delete expired keys themselves, master does that with DEL
+            let expired: Vec<_> = keys.iter()
+                .filter(|key| storage.get(*key).is_some_and(|x| x.is_expired()))
+                .cloned()
+                .collect();
+            let deleted = delete(storage, &keys);
+            for key in &deleted {
+                if expired.contains(key) {
+                    server.storage.touch_expired(key);
+                } else {
+                    server.storage.touch(key);
+                }
+                server.notify_keyspace_event(KeyspaceEventClass::Generic, "del", key);
+            }
+            if !deleted.is_empty() {
+                replicated.push(command);
+            }
+            Reply::Int(deleted.len() as i64)
+        },
+        QueuedCommand::Expire { key, expires_at, condition } => {
+            expire_if_needed(storage, &key);
+            let is_allowed = condition.allows(get_expiry(storage, &key), expires_at);
+            // same as in redis, a time in the past deletes the key right away, replicas wait for master's DEL instead
+            if is_allowed && (expires_at <= now_ts()) && !server.is_slave() {
+                let deleted = delete(storage, std::slice::from_ref(&key));
+                if deleted.is_empty() {
+                    return Reply::Int(0);
+                }
+                server.storage.touch(&key);
+                server.notify_keyspace_event(KeyspaceEventClass::Generic, "del", &key);
+                let command = Command::new(vec![b"DEL".to_vec(), key])
+                    .expect("hardcoded command should be valid");
+                replicated.push(command);
+                return Reply::Int(1);
+            }
+            let is_set = is_allowed && set_expiry(storage, &key, expires_at);
+            if is_set {
+                server.storage.touch(&key);
+                server.storage.add_volatile(&key);
+                server.notify_keyspace_event(KeyspaceEventClass::Generic, "expire", &key);
+                // always replicated with an absolute time, so that replicas expire the key at the same time
+                let command = Command::new(vec![
+                    b"PEXPIREAT".to_vec(),
+                    key,
+                    expires_at.to_string().into_bytes(),
+                ]).expect("pexpireat command should be valid");
+                replicated.push(command);
+            }
+            Reply::Int(is_set.into())
+        },
+        QueuedCommand::Keys => {
+            let keys = keys(storage).into_iter()
+                .map(Reply::BinaryString)
+                .collect();
+            Reply::Array(keys)
+        },
+        QueuedCommand::Type { key } => {
+            expire_if_needed(storage, &key);
+            Reply::SimpleString(get_value_kind(storage, &key).to_string())
+        },
+    }
+}
+
+/// Missing keys are fine for any command
+fn is_wrong_type(storage: &StorageInner, key: &StorageKey, expected_kind: &str) -> bool {
+    let kind = get_value_kind(storage, key);
+    kind != "none" && kind != expected_kind
+}
+
+fn simple_value_reply(value: Option<&SimpleValue>) -> Reply {
+    match value {
+        None => Reply::Null,
+        Some(SimpleValue::String(data)) => Reply::BinaryString(data.clone()),
+        Some(SimpleValue::Int(data)) => Reply::BinaryString(data.to_string().into_bytes()),
+    }
+}
+
+fn wrong_type_reply() -> Reply {
+    Reply::Error(ArgsError::WrongType.get_message().to_string())
+}
+
+fn check_can_write(connection: &Connection) -> HandleResult<()> {
+    if !connection.can_write() {
+        return Err(ArgsError::ReadOnlyReplica.into());
+    }
+    // the master has already accepted the write, so the replica has to apply it
+    if !connection.is_from_master() && !connection.server.has_enough_good_replicas() {
+        return Err(ArgsError::NoReplicas.into());
     }
     Ok(())
 }
 
-async fn info_replication(connection: &mut Connection) -> HandleResult<()> {
-    let result = format!(
-        "# Replication
-role:{}
-master_replid:{}
+/// Relative expiry is replaced with an absolute one in the replicated command, so that replicas expire the key at the same time.
+/// The conditions are checked by master, so the replicated command is only sent if it was applied, and has none of them.
+fn parse_set_args(args: &[Vec<u8>]) -> HandleResult<(StorageKey, StorageItemSimple, SetOptions, Command)> {
+    let (key, args) = split_arg(args)?;
+    let (value, args) = split_arg(args)?;
+    let (expiry, options) = parse_set_options(args)?;
+    let mut replicated = vec![b"SET".to_vec(), key.clone(), value.clone()];
+    if let Some(expires_at) = expiry {
+        replicated.push(b"PXAT".to_vec());
+        replicated.push(expires_at.to_string().into_bytes());
+    }
+    if options.keep_ttl {
+        replicated.push(b"KEEPTTL".to_vec());
+    }
+    let replicated = Command::new(replicated).expect("set command should be valid");
+    let item = StorageItemSimple::from_data(value.clone(), expiry);
+    Ok((key.clone(), item, options, replicated))
+}
+
+/// Same as in redis, unknown options, repeated expiry, and NX with XX or KEEPTTL with expiry are syntax errors
+fn parse_set_options(args: &[Vec<u8>]) -> HandleResult<(Option<ExpiryTs>, SetOptions)> {
+    let mut expiry = None;
+    let mut options = SetOptions::default();
+    let mut args = args;
+    while let Some((option, tail)) = args.split_first() {
+        args = tail;
+        let Some(option) = normalize_name(option) else {
+            return Err(SYNTAX_ERROR);
+        };
+        match option.as_str() {
+            "EX" | "PX" | "EXAT" | "PXAT" if expiry.is_none() && !options.keep_ttl => {
+                let (value, tail) = split_and_parse_value::<i64>(args)?;
+                args = tail;
+                let expires_at = Some(value).filter(|x| *x > 0)
+                    .and_then(|x| to_expiry_ts(&option, x))
+                    .ok_or(ArgsError::InvalidExpireTime("SET"))?;
+                expiry = Some(expires_at);
+            },
+            "KEEPTTL" if expiry.is_none() => options.keep_ttl = true,
+            "NX" if options.condition != Some(SetCondition::IfExists) => options.condition = Some(SetCondition::IfMissing),
+            "XX" if options.condition != Some(SetCondition::IfMissing) => options.condition = Some(SetCondition::IfExists),
+            "GET" => options.get = true,
+            _ => {
+                eprintln!("unsupported or conflicting set option {option}");
+                return Err(SYNTAX_ERROR);
+            },
+        }
+    }
+    Ok((expiry, options))
+}
+
+fn parse_expire_condition(args: &[Vec<u8>]) -> HandleResult<ExpireCondition> {
+    let mut condition = ExpireCondition::default();
+    for option in args {
+        match normalize_name(option).as_deref() {
+            Some("NX") => condition.nx = true,
+            Some("XX") => condition.xx = true,
+            Some("GT") => condition.gt = true,
+            Some("LT") => condition.lt = true,
+            _ => return Err(ArgsError::UnsupportedOption(String::from_utf8_lossy(option).into_owned()).into()),
+        }
+    }
+    if condition.nx && (condition.xx || condition.gt || condition.lt) {
+        return Err(ArgsError::IncompatibleNxOption.into());
+    }
+    if condition.gt && condition.lt {
+        return Err(ArgsError::IncompatibleGtLtOptions.into());
+    }
+    Ok(condition)
+}
+
+/// Converts the value of EX / PX / EXAT / PXAT options (and of the EXPIRE commands with the same suffixes) to a timestamp.
+/// None if it does not fit into 64 bits, same as in redis. Times before 1970 become 0, they are in the past anyway.
+fn to_expiry_ts(kind: &str, value: i64) -> Option<ExpiryTs> {
+    let in_millis = if kind.starts_with('P') { value } else { value.checked_mul(1000)? };
+    let timestamp = if kind.ends_with("AT") {
+        in_millis
+    } else {
+        in_millis.checked_add(i64::try_from(now_ts()).ok()?)?
+    };
+    Some(timestamp.max(0) as ExpiryTs)
+}
+
+
+fn info(connection: &Connection, args: &[Vec<u8>]) -> HandleResult<Reply> {
+    let mut result = String::new();
+    for section in args {
+        if section.eq_ignore_ascii_case(b"replication") {
+            result.push_str(&info_replication(connection));
+        } else if section.eq_ignore_ascii_case(b"server") {
+            result.push_str("# Server\n");
+        } else {
+            eprintln!("Unknown section {:?}", std::str::from_utf8(section));
+        }
+    }
+    Ok(Reply::Verbatim { format: "txt", text: result.into_bytes() })
+}
+
+fn info_replication(connection: &Connection) -> String {
+    let mut result = "# Replication\n".to_string();
+    let offset = connection.server.replication.read().expect("got poisoned lock")
+        .get_offset();
+    match connection.server.get_master_link() {
+        Some(link) => {
+            let link = link.read().expect("got poisoned lock");
+            let is_up = link.state == MasterLinkState::Connected;
+            result.push_str(format!(
+                "role:slave
+master_host:{}
+master_port:{}
+master_link_status:{}
+master_sync_in_progress:{}
+slave_repl_offset:{offset}
+",
+                link.host,
+                link.port,
+                if is_up { "up" } else { "down" },
+                (link.state == MasterLinkState::Sync) as u8,
+            ).as_str());
+            if !is_up {
+                let down_since = link.down_since
+                    .map(|x| x.elapsed().as_secs() as i64)
+                    .unwrap_or(-1);
+                result.push_str(format!("master_link_down_since_seconds:{down_since}\n").as_str());
+            }
+        },
+        None => result.push_str("role:master\n"),
+    }
+    {
+        let slave_state = connection.server.slave_state.read().expect("got poisoned lock");
+        let slaves: Vec<_> = slave_state.get_slaves().collect();
+        result.push_str(format!("connected_slaves:{}\n", slaves.len()).as_str());
+        for (index, slave) in slaves.into_iter().enumerate() {
+            result.push_str(format!(
+                "slave{index}:ip={},port={},state=online,offset={},lag={}\n",
+                slave.ip,
+                slave.port,
+                slave.offset,
+                slave.last_ack_at.elapsed().as_secs(),
+            ).as_str());
+        }
+    }
+    {
+        let replication = connection.server.replication.read().expect("got poisoned lock");
+        let backlog = replication.get_backlog();
+        // redis counts offsets from 1
+        result.push_str(format!(
+            "master_replid:{}
+master_replid2:{}
 master_repl_offset:{}
+second_repl_offset:{}
+repl_backlog_active:1
+repl_backlog_size:{}
+repl_backlog_first_byte_offset:{}
+repl_backlog_histlen:{}
 ",
-        if connection.server.is_slave { "slave" } else { "master" },
-        connection.server.replication_id,
-        connection.server.slave_read_offset.load(Ordering::Acquire),
-    );
-    write_binary_string(&mut connection.stream, result, true).await
-        .ok_or(HandleError::ResponseFailed)
+            replication.get_id(),
+            replication.get_id2(),
+            replication.get_offset(),
+            replication.get_second_offset().map(|x| x as i64 + 1).unwrap_or(-1),
+            backlog.get_size(),
+            backlog.get_start_offset() + 1,
+            backlog.get_length(),
+        ).as_str());
+    }
+    result
+}
+
+fn role(connection: &Connection) -> Reply {
+    let offset = connection.server.replication.read().expect("got poisoned lock")
+        .get_offset();
+    let string = |x: &str| Reply::BinaryString(x.as_bytes().to_vec());
+    match connection.server.get_master_link() {
+        Some(link) => {
+            let link = link.read().expect("got poisoned lock");
+            Reply::Array(vec![
+                string("slave"),
+                string(&link.host),
+                Reply::Int(link.port.into()),
+                string(link.state.as_str()),
+                Reply::Int(offset as i64),
+            ])
+        },
+        None => {
+            let slaves = connection.server.slave_state.read().expect("got poisoned lock")
+                .get_slaves()
+                .map(|x| Reply::Array(vec![
+                    string(&x.ip),
+                    string(&x.port.to_string()),
+                    string(&x.offset.to_string()),
+                ]))
+                .collect();
+            Reply::Array(vec![
+                string("master"),
+                Reply::Int(offset as i64),
+                Reply::Array(slaves),
+            ])
+        },
+    }
+}
+
+fn replicaof(connection: &mut Connection, command: &Command) -> HandleResult<Reply> {
+    if !connection.is_external() {
+        eprintln!("replicaof command was called via a wrong type of connection");
+        return Err(SYNTAX_ERROR);
+    }
+    let args = command.get_args();
+    let (host, args) = split_and_parse_str(args)?;
+    let (port, _) = split_arg(args)?;
+    if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case(b"one") {
+        connection.server.become_master();
+        connection.update_kind();
+        return Ok(Reply::SimpleString("OK".to_string()));
+    }
+    let port = parse_value::<u16>(port)?;
+    let is_changed = connection.server.become_slave(host.to_string(), port, true);
+    connection.update_kind();
+    let response = if is_changed { "OK" } else { "OK Already connected to specified master" };
+    Ok(Reply::SimpleString(response.to_string()))
 }
 
 async fn repl_conf(connection: &mut Connection, command: Command) -> HandleResult<()> {
     let args = command.get_args();
     let (subcommand, args) = split_subcommand(args)?;
     match subcommand.as_str() {
-        "CAPA" => repl_conf_capa(connection, args).await,
-        "LISTENING-PORT" => repl_conf_port(connection, args).await,
         "GETACK" => repl_conf_get_ack(connection, args).await,
         "ACK" => repl_conf_ack(connection, args).await,
+        _ => {
+            let reply = repl_conf_option(connection, command.get_args())?;
+            connection.add_reply(reply);
+            Ok(())
+        }
+    }
+}
+
+/// The subcommands that only set an option of the connection, and reply right away
+fn repl_conf_option(connection: &mut Connection, args: &[Vec<u8>]) -> HandleResult<Reply> {
+    let (subcommand, args) = split_subcommand(args)?;
+    match subcommand.as_str() {
+        "CAPA" => Ok(repl_conf_capa(connection, args)),
+        "LISTENING-PORT" => repl_conf_port(connection, args),
         _ => {
             eprintln!("unknown replconf subcommand {subcommand}");
-            Err(INVALID_ARGS_DEFAULT)
+            Err(SYNTAX_ERROR)
         }
     }
 }
 
-async fn repl_conf_capa(connection: &mut Connection, args: &[Vec<u8>]) -> HandleResult<()> {
-    split_and_assert_value(args, b"psync2")?;
-    write_simple_string(&mut connection.stream, "OK").await
-        .ok_or(HandleError::ResponseFailed)
+fn repl_conf_capa(connection: &mut Connection, args: &[Vec<u8>]) -> Reply {
+    // capabilities come as "capa eof capa psync2", the first "capa" is already consumed as the subcommand
+    for capability in args.iter().filter(|x| !x.eq_ignore_ascii_case(b"capa")) {
+        if capability.eq_ignore_ascii_case(b"eof") {
+            connection.supports_eof = true;
+        }
+    }
+    Reply::SimpleString("OK".to_string())
 }
 
-async fn repl_conf_port(connection: &mut Connection, args: &[Vec<u8>]) -> HandleResult<()> {
-    split_and_parse_value::<u16>(args)?;
-    write_simple_string(&mut connection.stream, "OK").await
-        .ok_or(HandleError::ResponseFailed)
+fn repl_conf_port(connection: &mut Connection, args: &[Vec<u8>]) -> HandleResult<Reply> {
+    let (port, _) = split_and_parse_value::<u16>(args)?;
+    connection.listening_port = Some(port);
+    Ok(Reply::SimpleString("OK".to_string()))
 }
 
 async fn repl_conf_get_ack(connection: &mut Connection, args: &[Vec<u8>]) -> HandleResult<()> {
-    if !connection.server.is_slave {
-        eprintln!("received replconf getack as master");
-        return Err(INVALID_ARGS_DEFAULT);
+    if !connection.is_from_master() {
+        eprintln!("received replconf getack not from master");
+        return Err(SYNTAX_ERROR);
     }
     split_and_assert_value(args, b"*")?;
-    let offset = connection.server.slave_read_offset.load(Ordering::Acquire).to_string();
-    write_array_of_strings(&mut connection.stream, ["REPLCONF", "ACK", offset.as_str()]).await
+    write_ack(connection).await
         .ok_or(HandleError::ResponseFailed)
 }
 
+pub(crate) async fn write_ack(connection: &mut Connection) -> Option<()> {
+    let offset = connection.server.replication.read().expect("got poisoned lock")
+        .get_offset().to_string();
+    write_array_of_strings(&mut connection.stream, ["REPLCONF", "ACK", offset.as_str()]).await
+}
+
 async fn repl_conf_ack(connection: &mut Connection, args: &[Vec<u8>]) -> HandleResult<()> {
     let (offset, _) = split_and_parse_value::<usize>(args)?;
     let success = connection.update_acknowledged_offset(offset);
     if !success {
-        return Err(INVALID_ARGS_DEFAULT);
+        return Err(SYNTAX_ERROR);
     }
     Ok(())
 }
@@ -269,99 +958,146 @@ async fn repl_conf_ack(connection: &mut Connection, args: &[Vec<u8>]) -> HandleR
 async fn wait(connection: &mut Connection, command: Command) -> HandleResult<()> {
     if !matches!(connection.kind, ConnectionKind::ServerMasterConnectionExternal{..}) {
         eprintln!("wait command was called via readonly connection");
-        return Err(INVALID_ARGS_DEFAULT);
-    }
-    let args = command.get_args();
-    let (need_count, args) = split_and_parse_value::<usize>(args)?;
-    let (timeout, _) = split_and_parse_value(args)?;
-    if timeout > 600000 {
-        eprintln!("timeout is too long");
-        return Err(INVALID_ARGS_DEFAULT);
+        return Err(SYNTAX_ERROR);
     }
+    let (need_count, timeout_ms) = parse_wait_args(command.get_args())?;
 
     let need_offset = connection.get_replicated_offset();
+    // subscribing before the first check, so that acks arriving in between are not missed
+    let acks = connection.server.slave_state.read().expect("got poisoned lock")
+        .subscribe_acks();
     let (acked_count, waiting_count) = connection.check_acknowledged_replicas(need_offset);
     let acked_count = if (waiting_count > 0) && (acked_count < need_count) {
-        // todo: refactor this
-        let command = Command{
-            byte_size: 37,
-            name: "REPLCONF".to_string(),
-            raw: vec![
-                b"REPLCONF".to_vec(),
-                b"GETACK".to_vec(),
-                b"*".to_vec()
-            ],
-        };
+        let command = Command::new(vec![
+            b"REPLCONF".to_vec(),
+            b"GETACK".to_vec(),
+            b"*".to_vec()
+        ]).expect("hardcoded command should be valid");
         connection.replicate(command);
-        sleep(Duration::from_millis(timeout)).await;
-        let (acked_count, _) = connection.check_acknowledged_replicas(need_offset);
-        acked_count
+        // the replies to the pipelined commands before WAIT should not wait for the replicas
+        connection.flush().await.ok_or(HandleError::ResponseFailed)?;
+        let wait_future = connection.server.wait_acknowledged_replicas(need_offset, need_count, acks);
+        // zero timeout means waiting forever
+        if timeout_ms == 0 {
+            wait_future.await
+        } else {
+            match timeout(Duration::from_millis(timeout_ms), wait_future).await {
+                Ok(x) => x,
+                Err(_) => connection.check_acknowledged_replicas(need_offset).0,
+            }
+        }
     } else {
         acked_count
     };
     
-    write_int(&mut connection.stream, acked_count as i64).await
-        .ok_or(HandleError::ResponseFailed)
+    connection.add_reply(Reply::Int(acked_count as i64));
+    Ok(())
 }
 
-async fn config(connection: &mut Connection, command: Command) -> HandleResult<()> {
-    let args = command.get_args();
+/// Transactions can't block, so only the replicas that have already acknowledged the writes are counted
+fn wait_without_blocking(connection: &Connection, args: &[Vec<u8>]) -> HandleResult<Reply> {
+    if !matches!(connection.kind, ConnectionKind::ServerMasterConnectionExternal{..}) {
+        eprintln!("wait command was called via readonly connection");
+        return Err(SYNTAX_ERROR);
+    }
+    parse_wait_args(args)?;
+    let (acked_count, _) = connection.check_acknowledged_replicas(connection.get_replicated_offset());
+    Ok(Reply::Int(acked_count as i64))
+}
+
+fn parse_wait_args(args: &[Vec<u8>]) -> HandleResult<(usize, u64)> {
+    let (need_count, args) = split_and_parse_value::<usize>(args)?;
+    let (timeout_ms, _) = split_and_parse_value::<u64>(args)?;
+    if timeout_ms > 600000 {
+        eprintln!("timeout is too long");
+        return Err(SYNTAX_ERROR);
+    }
+    Ok((need_count, timeout_ms))
+}
+
+fn config(connection: &Connection, args: &[Vec<u8>]) -> HandleResult<Reply> {
     let (subcommand, args) = split_subcommand(args)?;
     match subcommand.as_str() {
-        "GET" => config_get(connection, args).await,
+        "GET" => config_get(connection, args),
         _ => {
             eprintln!("unknown config subcommand {subcommand}");
-            Err(INVALID_ARGS_DEFAULT)
+            Err(SYNTAX_ERROR)
         }
     }
 }
 
-async fn config_get(connection: &mut Connection, args: &[Vec<u8>]) -> HandleResult<()> {
+fn config_get(connection: &Connection, args: &[Vec<u8>]) -> HandleResult<Reply> {
     let (key, _) = split_and_parse_str(args)?;
-    match connection.server.config.get(key) {
-        Some(value) => write_array_of_strings(&mut connection.stream, [key.as_bytes(), value]).await,
-        None => write_null(&mut connection.stream).await,
-    }.ok_or(HandleError::ResponseFailed)
+    let reply = match connection.server.config.get(key) {
+        Some(value) => Reply::Map(vec![(
+            Reply::BinaryString(key.as_bytes().to_vec()),
+            Reply::BinaryString(value.clone()),
+        )]),
+        None => Reply::Null,
+    };
+    Ok(reply)
 }
 
-async fn keys(connection: &mut Connection, command: Command) -> HandleResult<()> {
-    let args = command.get_args();
-    split_and_assert_value(args, b"*")?;
-    let keys = connection.server.storage.keys();
-    write_array_of_strings(&mut connection.stream, keys).await
-        .ok_or(HandleError::ResponseFailed)
+/// Lets the clients learn which commands are supported, and where their keys are
+/// Renamed commands are shown with their new names, and disabled ones are not shown at all
+fn command_introspection(connection: &Connection, args: &[Vec<u8>]) -> HandleResult<Reply> {
+    let names = &connection.server.command_names;
+    let lookup = |name: &[u8]| -> Option<(&str, &'static CommandSpec)> {
+        let spec = names.lookup(&normalize_name(name)?)?;
+        Some((names.get_name(spec)?, spec))
+    };
+    let visible: Vec<_> = all_commands().iter()
+        .filter_map(|spec| Some((names.get_name(spec)?, spec)))
+        .collect();
+    if args.is_empty() {
+        return Ok(Reply::Array(visible.into_iter().map(command_info_reply).collect()));
+    }
+    let (subcommand, args) = split_subcommand(args)?;
+    let reply = match subcommand.as_str() {
+        "COUNT" => Reply::Int(visible.len() as i64),
+        "INFO" if args.is_empty() => Reply::Array(visible.into_iter().map(command_info_reply).collect()),
+        "INFO" => {
+            let replies = args.iter()
+                .map(|name| match lookup(name) {
+                    Some(command) => command_info_reply(command),
+                    None => Reply::Null,
+                })
+                .collect();
+            Reply::Array(replies)
+        },
+        _ => {
+            eprintln!("unknown command subcommand {subcommand}");
+            return Err(SYNTAX_ERROR);
+        },
+    };
+    Ok(reply)
 }
 
-async fn handle_type(connection: &mut Connection, command: Command) -> HandleResult<()> {
-    let args = command.get_args();
-    let (key, _) = split_arg(args)?;
-    let kind = connection.server.storage.get_value_kind(key);
-    write_simple_string(&mut connection.stream, kind).await
-        .ok_or(HandleError::ResponseFailed)
+/// Same format as in redis 7, but without the key specs and the subcommands
+fn command_info_reply((name, spec): (&str, &CommandSpec)) -> Reply {
+    let flags = spec.flags.iter()
+        .map(|x| Reply::SimpleString(x.name().to_string()))
+        .collect();
+    let categories = spec.categories.iter()
+        .map(|x| Reply::SimpleString(x.name().to_string()))
+        .collect();
+    Reply::Array(vec![
+        Reply::BinaryString(name.to_lowercase().into_bytes()),
+        Reply::Int(spec.arity),
+        Reply::Set(flags),
+        Reply::Int(spec.first_key),
+        Reply::Int(spec.last_key),
+        Reply::Int(spec.key_step),
+        Reply::Set(categories),
+        // tips
+        Reply::Array(Vec::new()),
+        // key specs
+        Reply::Array(Vec::new()),
+        // subcommands
+        Reply::Array(Vec::new()),
+    ])
 }
 
-async fn xadd(connection: &mut Connection, command: Command) -> HandleResult<()> {
-    if !connection.can_replicate() {
-        eprintln!("xadd command was called via readonly connection");
-        return Err(INVALID_ARGS_DEFAULT);
-    }
-    let (key, item) = parse_xadd_args(command.get_args())?;
-    if let Some(transaction) = connection.get_transaction_mut() {
-        if transaction.started {
-            transaction.queue.push(QueuedCommand::Xadd{key, item, command});
-            return write_simple_string(&mut connection.stream, "QUEUED").await
-                .ok_or(HandleError::ResponseFailed);
-        }
-    }
-    let id = item.id.clone(); // todo: would it be possible not to clone it?
-    do_xadd(connection, key, item, command)?;
-    if connection.server.is_slave {
-        Ok(())
-    } else {
-        write_binary_string(&mut connection.stream, id, true).await
-            .ok_or(HandleError::ResponseFailed)
-    }
-}
 fn parse_xadd_args(args: &[Vec<u8>]) -> HandleResult<(StorageKey, StreamEntry)> {
     let (key, args) = split_arg(args)?;
     let (item_id, args) = split_arg(args)?;
@@ -380,129 +1116,84 @@ fn parse_xadd_args(args: &[Vec<u8>]) -> HandleResult<(StorageKey, StreamEntry)>
     };
     Ok((key.clone(), item))
 }
-fn do_xadd(connection: &mut Connection, key: StorageKey, item: StreamEntry, command: Command) -> ExecResult<()> {
-    /*
-    We need to ensure that replicas have exactly the same state as master,
-    so if there are concurrent updates to the same key, replicas need to receive them in the same order as they were applied in master,
-    so sending commands to replicas should be done under the same lock as the updates.
-     */
-    let Some(guard) = connection.server.storage.append_to_stream(key, item) else {
-        eprintln!("can't do xadd when key is not a stream");
-        return Err(ArgsError::Generic);
-    };
-    connection.replicate(command);
-    drop(guard); // guard is unused, it just needs to exist until the end of scope
-    Ok(())
-}
-
-async fn incr(connection: &mut Connection, command: Command) -> HandleResult<()> {
-    if !connection.can_replicate() {
-        eprintln!("incr command was called via readonly connection");
-        return Err(INVALID_ARGS_DEFAULT);
-    }
-    let (key, _args) = split_arg(command.get_args())?;
-    let key = key.clone();
-    if let Some(transaction) = connection.get_transaction_mut() {
-        if transaction.started {
-            transaction.queue.push(QueuedCommand::Incr{key, command});
-            return write_simple_string(&mut connection.stream, "QUEUED").await
-                .ok_or(HandleError::ResponseFailed);
-        }
-    }
-    let new_value = do_incr(connection, key, command)?;
-    if connection.server.is_slave {
-        Ok(())
-    } else {
-        write_int(&mut connection.stream, new_value).await
-            .ok_or(HandleError::ResponseFailed)
-    }
-}
-
-fn do_incr(connection: &mut Connection, key: StorageKey, command: Command) -> ExecResult<i64> {
-    /*
-    We need to ensure that replicas have exactly the same state as master,
-    so if there are concurrent updates to the same key, replicas need to receive them in the same order as they were applied in master,
-    so sending commands to replicas should be done under the same lock as the updates.
-     */
-    let Some((guard, value)) = connection.server.storage.increment(key) else {
-        eprintln!("can't do incr when key is not an int");
-        return Err(ArgsError::CanNotIncrementThisValue);
-    };
-    connection.replicate(command);
-    drop(guard); // guard is unused, it just needs to exist until the end of scope
-    Ok(value)
-}
-
 async fn multi(connection: &mut Connection) -> HandleResult<()> {
     let Some(transaction) = connection.get_transaction_mut() else {
         eprintln!("multi command was called on a wrong type of connection");
-        return Err(INVALID_ARGS_DEFAULT);
+        return Err(SYNTAX_ERROR);
     };
+    if transaction.started {
+        return Err(ArgsError::NestedMulti.into());
+    }
     transaction.started = true;
-    write_simple_string(&mut connection.stream, "OK").await
-        .ok_or(HandleError::ResponseFailed)
+    connection.add_reply(Reply::SimpleString("OK".to_string()));
+    Ok(())
 }
 
 async fn exec(connection: &mut Connection) -> HandleResult<()> {
     let Some(transaction) = connection.get_transaction_mut() else {
         eprintln!("exec command was called on a wrong type of connection");
-        return Err(INVALID_ARGS_DEFAULT);
+        return Err(SYNTAX_ERROR);
     };
     if !transaction.started {
         return Err(HandleError::InvalidArgs(ArgsError::ExecWithoutMulti));
     }
 
     transaction.started = false;
-    let queue = mem::replace(&mut transaction.queue, vec![]);
-    let queue_size = queue.len();
-
-    write_array_size(&mut connection.stream, queue_size).await
-        .ok_or(HandleError::ResponseFailed)?;
-    for command in queue {
-        match command {
-            QueuedCommand::Get { key } => {
-                exec_get(connection, &key).await?
-            }
-            QueuedCommand::Set { key, item, command } => {
-                do_set(connection, key, item, command);
-                write_simple_string(&mut connection.stream, "OK").await
-                    .ok_or(HandleError::ResponseFailed)?;
-            }
-            QueuedCommand::Xadd { key, item, command } => {
-                let res = do_xadd(connection, key, item, command);
-                match res {
-                    Ok(_) => {
-                        write_simple_string(&mut connection.stream, "OK").await
-                            .ok_or(HandleError::ResponseFailed)?;
-                    },
-                    Err(err) => {
-                        write_simple_error(&mut connection.stream, err.get_message()).await
-                            .ok_or(HandleError::ResponseFailed)?;
-                    },
-                }
-            }
-            QueuedCommand::Incr { key, command } => {
-                let res = do_incr(connection, key, command);
-                match res {
-                    Ok(new_value) => {
-                        write_int(&mut connection.stream, new_value).await
-                            .ok_or(HandleError::ResponseFailed)?;
-                    },
-                    Err(err) => {
-                        write_simple_error(&mut connection.stream, err.get_message()).await
-                            .ok_or(HandleError::ResponseFailed)?;
-                    },
-                }
-            }
-        }
+    let queue = std::mem::take(&mut transaction.queue);
+    let has_errors = std::mem::take(&mut transaction.has_errors);
+    let watched = transaction.watched.clone();
+    if has_errors {
+        connection.unwatch_all();
+        return Err(ArgsError::ExecAbort.into());
     }
+    let replies = exec_transaction(connection, queue, &watched);
+    connection.unwatch_all();
+    let reply = match replies {
+        Some(replies) => Reply::Array(replies),
+        None => Reply::NullArray,
+    };
+    connection.add_reply(reply);
     Ok(())
 }
 
+/// The whole transaction is applied under one lock, so that nobody can see or change the data in between.
+/// Returns None without applying anything if some of the watched keys were modified.
+fn exec_transaction(connection: &mut Connection, queue: Vec<Command>, watched: &[(StorageKey, WatchedVersion)]) -> Option<Vec<Reply>> {
+    let server = Arc::clone(&connection.server);
+    let mut guard = server.storage.write_all();
+    let is_modified = watched.iter()
+        .any(|(key, version)| server.storage.is_modified(&guard, key, version));
+    if is_modified {
+        return None;
+    }
+    let mut replicated = Vec::new();
+    let replies = queue.into_iter()
+        .flat_map(|command| {
+            let replies = if is_data_command(&command.name) {
+                parse_queued(command)
+                    .map(|command| vec![exec_queued(connection, &mut guard, command, &mut replicated)])
+            } else if is_connection_command(&command.name) {
+                exec_connection(connection, &command)
+            } else {
+                exec_simple(connection, &command, &mut replicated).map(|x| vec![x])
+            };
+            replies.unwrap_or_else(|err| vec![err.into_reply()])
+        })
+        .collect();
+    connection.replicate_all(replicated);
+    drop(guard);
+    Some(replies)
+}
+
+/// Applies a MULTI / EXEC block that was received from master
+pub(crate) fn exec_replicated_transaction(connection: &mut Connection, commands: Vec<Command>) {
+    exec_transaction(connection, commands, &[]);
+}
+
 async fn discard(connection: &mut Connection) -> HandleResult<()> {
     let Some(transaction) = connection.get_transaction_mut() else {
         eprintln!("discard command was called on a wrong type of connection");
-        return Err(INVALID_ARGS_DEFAULT);
+        return Err(SYNTAX_ERROR);
     };
     if !transaction.started {
         return Err(HandleError::InvalidArgs(ArgsError::DiscardWithoutMulti));
@@ -510,16 +1201,221 @@ async fn discard(connection: &mut Connection) -> HandleResult<()> {
 
     transaction.started = false;
     transaction.queue = vec![];
-    
-    write_simple_string(&mut connection.stream, "OK").await
-        .ok_or(HandleError::ResponseFailed)
+    transaction.has_errors = false;
+    connection.unwatch_all();
+
+    connection.add_reply(Reply::SimpleString("OK".to_string()));
+    Ok(())
+}
+
+async fn watch(connection: &mut Connection, command: Command) -> HandleResult<()> {
+    let keys = command.get_args();
+    if keys.is_empty() {
+        return Err(SYNTAX_ERROR);
+    }
+    let server = Arc::clone(&connection.server);
+    let Some(transaction) = connection.get_transaction_mut() else {
+        eprintln!("watch command was called on a wrong type of connection");
+        return Err(SYNTAX_ERROR);
+    };
+    if transaction.started {
+        return Err(HandleError::InvalidArgs(ArgsError::WatchInsideMulti));
+    }
+    for key in keys {
+        let version = server.storage.watch(key);
+        transaction.watched.push((key.clone(), version));
+    }
+    connection.add_reply(Reply::SimpleString("OK".to_string()));
+    Ok(())
+}
+
+async fn unwatch(connection: &mut Connection) -> HandleResult<()> {
+    if connection.get_transaction_mut().is_none() {
+        eprintln!("unwatch command was called on a wrong type of connection");
+        return Err(SYNTAX_ERROR);
+    }
+    connection.unwatch_all();
+    connection.add_reply(Reply::SimpleString("OK".to_string()));
+    Ok(())
+}
+
+fn subscribe(connection: &mut Connection, command: &Command, kind: SubscriptionKind) -> HandleResult<Vec<Reply>> {
+    let channels = command.get_args();
+    if !connection.is_external() || channels.is_empty() {
+        eprintln!("{} command was called with wrong args or via a wrong connection", command.name);
+        return Err(SYNTAX_ERROR);
+    }
+    let mut pubsub = connection.server.pubsub.write().expect("got poisoned lock");
+    let subscriber = connection.subscriber.get_or_insert_with(|| pubsub.new_subscriber());
+    let replies = channels.iter()
+        .map(|channel| {
+            pubsub.subscribe(subscriber, kind, channel);
+            subscription_reply(kind.subscribe_reply_name(), Some(channel), subscriber.count(kind))
+        })
+        .collect();
+    Ok(replies)
+}
+
+fn unsubscribe(connection: &mut Connection, command: &Command, kind: SubscriptionKind) -> Vec<Reply> {
+    let name = kind.unsubscribe_reply_name();
+    let Some(subscriber) = &mut connection.subscriber else {
+        // nothing to unsubscribe from, but each channel still gets its own reply, same as in redis
+        return match command.get_args() {
+            [] => vec![subscription_reply(name, None, 0)],
+            channels => channels.iter().map(|x| subscription_reply(name, Some(x), 0)).collect(),
+        };
+    };
+    // without arguments, the connection is unsubscribed from everything
+    let channels = match command.get_args() {
+        [] => subscriber.subscriptions(kind).iter().cloned().collect(),
+        channels => channels.to_vec(),
+    };
+    let mut replies: Vec<_> = {
+        let mut pubsub = connection.server.pubsub.write().expect("got poisoned lock");
+        channels.iter()
+            .map(|channel| {
+                pubsub.unsubscribe(subscriber, kind, channel);
+                subscription_reply(name, Some(channel), subscriber.count(kind))
+            })
+            .collect()
+    };
+    if replies.is_empty() {
+        replies.push(subscription_reply(name, None, subscriber.count(kind)));
+    }
+    replies
+}
+
+fn subscription_reply(name: &str, channel: Option<&Vec<u8>>, count: usize) -> Reply {
+    Reply::Push(vec![
+        Reply::BinaryString(name.as_bytes().to_vec()),
+        channel.map_or(Reply::Null, |x| Reply::BinaryString(x.clone())),
+        Reply::Int(count as i64),
+    ])
+}
+
+fn publish(connection: &Connection, args: &[Vec<u8>]) -> HandleResult<Reply> {
+    let (channel, args) = split_arg(args)?;
+    let (message, _) = split_arg(args)?;
+    let count = connection.server.pubsub.read().expect("got poisoned lock")
+        .publish(channel, message);
+    Ok(Reply::Int(count as i64))
+}
+
+/// Shard channel messages are replicated, and delivered to the subscribers of replicas too
+fn shard_publish(connection: &Connection, args: &[Vec<u8>]) -> HandleResult<Reply> {
+    let (channel, args) = split_arg(args)?;
+    let (message, _) = split_arg(args)?;
+    let count = connection.server.pubsub.read().expect("got poisoned lock")
+        .publish_to_shard(channel, message);
+    Ok(Reply::Int(count as i64))
+}
+
+fn pubsub(connection: &Connection, args: &[Vec<u8>]) -> HandleResult<Reply> {
+    let (subcommand, args) = split_subcommand(args)?;
+    let pubsub = connection.server.pubsub.read().expect("got poisoned lock");
+    let reply = match subcommand.as_str() {
+        "CHANNELS" | "SHARDCHANNELS" => {
+            let kind = if subcommand == "CHANNELS" { SubscriptionKind::Channel } else { SubscriptionKind::ShardChannel };
+            let channels = pubsub.get_channels(kind, args.first().map(|x| x.as_slice()));
+            Reply::Array(channels.into_iter().map(Reply::BinaryString).collect())
+        },
+        "NUMSUB" | "SHARDNUMSUB" => {
+            let kind = if subcommand == "NUMSUB" { SubscriptionKind::Channel } else { SubscriptionKind::ShardChannel };
+            // a flat list of pairs, even with RESP3, same as in redis
+            let counts = args.iter()
+                .flat_map(|channel| [
+                    Reply::BinaryString(channel.clone()),
+                    Reply::Int(pubsub.count_subscribers(kind, channel) as i64),
+                ])
+                .collect();
+            Reply::Array(counts)
+        },
+        "NUMPAT" => Reply::Int(pubsub.count_patterns() as i64),
+        _ => {
+            eprintln!("unknown pubsub subcommand {subcommand}");
+            return Err(SYNTAX_ERROR);
+        },
+    };
+    Ok(reply)
+}
+
+/// Switches the protocol, and tells the client about the server
+fn hello(connection: &mut Connection, command: &Command) -> HandleResult<Reply> {
+    let mut args = command.get_args();
+    let mut protocol = connection.protocol;
+    if let Some((version, tail)) = args.split_first() {
+        args = tail;
+        let version = parse_value::<i64>(version)?;
+        protocol = Protocol::from_version(version).ok_or(ArgsError::NoProto)?;
+    }
+    let mut name = None;
+    while let Some((option, tail)) = args.split_first() {
+        args = tail;
+        match normalize_name(option).as_deref() {
+            Some("AUTH") => {
+                let (user, tail) = split_arg(args)?;
+                let (_password, tail) = split_arg(tail)?;
+                args = tail;
+                // there are no passwords yet, so only the default user exists, and it doesn't need one
+                if user.as_slice() != b"default" {
+                    return Err(ArgsError::WrongPass.into());
+                }
+            },
+            Some("SETNAME") => {
+                let (value, tail) = split_arg(args)?;
+                args = tail;
+                if value.iter().any(|x| !x.is_ascii_graphic()) {
+                    return Err(ArgsError::InvalidClientName.into());
+                }
+                name = Some(value.clone());
+            },
+            _ => {
+                eprintln!("unknown hello option {:?}", std::str::from_utf8(option));
+                return Err(SYNTAX_ERROR);
+            },
+        }
+    }
+    connection.protocol = protocol;
+    if name.is_some() {
+        connection.name = name;
+    }
+    let string = |x: &str| Reply::BinaryString(x.as_bytes().to_vec());
+    let role = if connection.server.is_slave() { "replica" } else { "master" };
+    let reply = Reply::Map(vec![
+        (string("server"), string("redis")),
+        (string("version"), string(REDIS_VERSION)),
+        (string("proto"), Reply::Int(protocol.get_version())),
+        (string("id"), Reply::Int(connection.id as i64)),
+        (string("mode"), string("standalone")),
+        (string("role"), string(role)),
+        (string("modules"), Reply::Array(vec![])),
+    ]);
+    Ok(reply)
+}
+
+async fn quit(connection: &mut Connection) -> HandleResult<()> {
+    connection.add_reply(Reply::SimpleString("OK".to_string()));
+    Err(HandleError::Quit)
+}
+
+/// Brings the connection back to its initial state
+async fn reset(connection: &mut Connection) -> HandleResult<()> {
+    connection.unwatch_all();
+    connection.unsubscribe_all();
+    if let Some(transaction) = connection.get_transaction_mut() {
+        *transaction = Default::default();
+    }
+    connection.protocol = Protocol::Resp2;
+    connection.name = None;
+    connection.add_reply(Reply::SimpleString("RESET".to_string()));
+    Ok(())
 }
 
 fn split_subcommand(args: &[Vec<u8>]) -> HandleResult<(String, &[Vec<u8>])> {
     let (subcommand, args) = split_arg(args)?;
     let Some(subcommand) = normalize_name(subcommand) else {
         eprintln!("invalid subcommand name");
-        return Err(INVALID_ARGS_DEFAULT);
+        return Err(SYNTAX_ERROR);
     };
     Ok((subcommand, args))
 }
@@ -539,18 +1435,19 @@ fn split_and_parse_str(args: &[Vec<u8>]) -> HandleResult<(&str, &[Vec<u8>])> {
 fn split_arg(args: &[Vec<u8>]) -> HandleResult<(&Vec<u8>, &[Vec<u8>])> {
     let Some((value, args)) = args.split_first() else {
         eprintln!("missing parameter");
-        return Err(INVALID_ARGS_DEFAULT);
+        return Err(SYNTAX_ERROR);
     };
     Ok((value, args))
 }
 
+/// Only used for numbers, so the error is the same as in redis for the values that are not integers
 fn parse_value<T: FromStr>(value: &[u8]) -> HandleResult<T> {
-    let value = parse_str(value)?;
+    let value = parse_str(value).map_err(|_| ArgsError::NotAnInteger)?;
     match value.parse::<T>() {
         Ok(x) => Ok(x),
         Err(_) => {
             eprintln!("value is not valid: {value}");
-            Err(INVALID_ARGS_DEFAULT)
+            Err(ArgsError::NotAnInteger.into())
         }
     }
 }
@@ -560,7 +1457,7 @@ fn parse_str(value: &[u8]) -> HandleResult<&str> {
         Ok(x) => Ok(x),
         Err(error) => {
             eprintln!("value is not a valid string {error}");
-            Err(INVALID_ARGS_DEFAULT)
+            Err(SYNTAX_ERROR)
         }
     }
 }
@@ -569,7 +1466,7 @@ fn split_and_assert_value<'a>(args: &'a [Vec<u8>], expected: &[u8]) -> HandleRes
     let (value, args) = split_arg(args)?;
     if value != expected {
         eprintln!("unexpected parameter value");
-        return Err(INVALID_ARGS_DEFAULT);
+        return Err(SYNTAX_ERROR);
     }
     Ok(args)
 }
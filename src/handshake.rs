@@ -1,62 +1,115 @@
 use std::time::Duration;
 use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
 use tokio::time::timeout;
+use crate::rdb::RdbLoader;
 use crate::resp::*;
+use crate::storage::StorageInner;
 
-pub(crate) async fn master_handshake(stream: &mut (impl AsyncBufReadExt + AsyncWriteExt + Unpin), my_port: u16) -> (String, usize) {
+pub(crate) enum SyncKind {
+    FullResync{replication_id: String, offset: usize},
+    Continue{replication_id: Option<String>},
+}
+
+/// None for replication_id means that we don't have any data from master yet, and need a full resync.
+pub(crate) async fn master_handshake(stream: &mut (impl AsyncBufReadExt + AsyncWriteExt + Unpin), my_port: u16, replication_id: Option<&str>, offset: usize) -> Option<SyncKind> {
     let buf = &mut [0u8; 512];
-    write(stream, ["PING"]).await;
-    read_expect(stream, buf, "+PONG\r\n").await;
-    write(stream, ["REPLCONF", "listening-port", my_port.to_string().as_str()]).await;
-    read_expect(stream, buf, "+OK\r\n").await;
-    write(stream, ["REPLCONF", "capa", "psync2"]).await;
-    read_expect(stream, buf, "+OK\r\n").await;
-    write(stream, ["PSYNC", "?", "-1"]).await;
-    let master_config = read_simple_string(stream, 100).await
-        .expect("failed to get config from master");
-    let result = parse_master_config(&master_config);
-    read_binary_string(stream, false).await
-        .expect("failed to get file from master");
-    result
+    write(stream, ["PING"]).await?;
+    read_expect(stream, buf, "+PONG\r\n").await?;
+    write(stream, ["REPLCONF", "listening-port", my_port.to_string().as_str()]).await?;
+    read_expect(stream, buf, "+OK\r\n").await?;
+    write(stream, ["REPLCONF", "capa", "eof", "capa", "psync2"]).await?;
+    read_expect(stream, buf, "+OK\r\n").await?;
+    match replication_id {
+        // master expects the offset of the next byte that we need
+        Some(id) => write(stream, ["PSYNC", id, (offset + 1).to_string().as_str()]).await?,
+        None => write(stream, ["PSYNC", "?", "-1"]).await?,
+    };
+    let Some(master_config) = read_simple_string(stream, 100).await else {
+        eprintln!("failed to get config from master");
+        return None;
+    };
+    parse_master_config(&master_config)
+}
+
+pub(crate) async fn receive_rdb(stream: &mut (impl AsyncBufReadExt + Unpin)) -> Option<StorageInner> {
+    /*
+    The file is loaded while it's being received, with or without a known size.
+    Its size is not limited, same as in redis: it's never held in memory as a whole.
+     */
+    let mut loader = RdbLoader::default();
+    let res = read_binary_string_chunks(stream, |chunk| loader.feed(chunk)).await;
+    if res.is_none() {
+        eprintln!("failed to get file from master");
+        return None;
+    }
+    loader.finish()
 }
 
-async fn write<S: AsRef<[u8]>>(stream: &mut (impl AsyncWriteExt + Unpin), message: impl AsRef<[S]>) {
-    write_array_of_strings(stream, message)
-        .await
-        .expect("failed to write message during handshake with master");
+async fn write<S: AsRef<[u8]>>(stream: &mut (impl AsyncWriteExt + Unpin), message: impl AsRef<[S]>) -> Option<()> {
+    let res = write_array_of_strings(stream, message).await;
+    if res.is_none() {
+        eprintln!("failed to write message during handshake with master");
+    }
+    res
 }
 
-async fn read<'a>(stream: &mut (impl AsyncBufReadExt + Unpin), buf: &'a mut [u8]) -> &'a [u8] {
-    let read_size = timeout(
-        Duration::from_millis(1000), 
+async fn read<'a>(stream: &mut (impl AsyncBufReadExt + Unpin), buf: &'a mut [u8]) -> Option<&'a [u8]> {
+    let res = timeout(
+        Duration::from_millis(1000),
         stream.read(buf)
-    )
-        .await
-        .expect("timeout when reading during handshake")
-        .expect("failed to read message during handshake");
+    ).await;
+    let read_size = match res {
+        Ok(Ok(x)) => x,
+        Ok(Err(err)) => {
+            eprintln!("failed to read message during handshake {err}");
+            return None;
+        },
+        Err(_) => {
+            eprintln!("timeout when reading during handshake");
+            return None;
+        },
+    };
     if read_size == 0 {
-        panic!("got EOF from master during handshake");
+        eprintln!("got EOF from master during handshake");
+        return None;
     }
-    &buf[..read_size]
+    Some(&buf[..read_size])
 }
 
-async fn read_expect(stream: &mut (impl AsyncBufReadExt + Unpin), buf: &mut [u8], expected: &str) {
-    let response = read(stream, buf).await;
+async fn read_expect(stream: &mut (impl AsyncBufReadExt + Unpin), buf: &mut [u8], expected: &str) -> Option<()> {
+    let response = read(stream, buf).await?;
     if response != expected.as_bytes() {
-        panic!(
+        eprintln!(
             "unexpected response from master: expected {expected}, got {:?}",
             std::str::from_utf8(response)
         );
+        return None;
     }
+    Some(())
 }
 
-fn parse_master_config(buf: &str) -> (String, usize) {
-    let buf = buf.strip_prefix("FULLRESYNC ")
-        .expect("Missing prefix in master config response");
-    let (id, offset) = buf.split_once(' ')
-        .expect("Failed to split the master config response");
-    assert_eq!(id.len(), 40, "Invalid length of master id");
-    let offset = offset.parse()
-        .expect("Failed to parse master offset");
-    (id.to_string(), offset)
+fn parse_master_config(buf: &str) -> Option<SyncKind> {
+    if let Some(replication_id) = buf.strip_prefix("CONTINUE") {
+        // older masters do not send the id, which means that it did not change
+        let replication_id = replication_id.trim_start();
+        let replication_id = (!replication_id.is_empty()).then(|| replication_id.to_string());
+        return Some(SyncKind::Continue{replication_id});
+    }
+    let Some(buf) = buf.strip_prefix("FULLRESYNC ") else {
+        eprintln!("Missing prefix in master config response {buf}");
+        return None;
+    };
+    let Some((id, offset)) = buf.split_once(' ') else {
+        eprintln!("Failed to split the master config response {buf}");
+        return None;
+    };
+    if id.len() != 40 {
+        eprintln!("Invalid length of master id {id}");
+        return None;
+    }
+    let Ok(offset) = offset.parse() else {
+        eprintln!("Failed to parse master offset {offset}");
+        return None;
+    };
+    Some(SyncKind::FullResync{replication_id: id.to_string(), offset})
 }
@@ -0,0 +1,197 @@
+/*
+Listpack is the compact list encoding that redis uses in RDB files, e.g. for the entries of streams.
+It starts with the total size in bytes and the number of elements, and ends with 0xFF.
+Each element is its encoding with the data, followed by the size of these two, written backwards,
+so that the list can also be walked from the end.
+ */
+
+const HEADER_SIZE: usize = 6;
+const END_MARK: u8 = 0xFF;
+/// the number of elements is only stored up to this value, larger lists have to be counted
+const MAX_STORED_COUNT: usize = u16::MAX as usize;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ListpackValue {
+    Int(i64),
+    String(Vec<u8>),
+}
+
+pub(crate) fn encode_listpack(values: &[ListpackValue]) -> Vec<u8> {
+    let mut result = vec![0; HEADER_SIZE];
+    for value in values {
+        let start = result.len();
+        match value {
+            ListpackValue::Int(x) => encode_int(&mut result, *x),
+            ListpackValue::String(x) => encode_string(&mut result, x),
+        }
+        let size = result.len() - start;
+        encode_back_size(&mut result, size);
+    }
+    result.push(END_MARK);
+    let total_size = result.len() as u32;
+    let count = values.len().min(MAX_STORED_COUNT) as u16;
+    result[..4].copy_from_slice(&total_size.to_le_bytes());
+    result[4..HEADER_SIZE].copy_from_slice(&count.to_le_bytes());
+    result
+}
+
+fn encode_int(result: &mut Vec<u8>, value: i64) {
+    if (0..128).contains(&value) {
+        result.push(value as u8);
+    } else {
+        result.push(0xF4);
+        result.extend_from_slice(&value.to_le_bytes());
+    }
+}
+
+fn encode_string(result: &mut Vec<u8>, value: &[u8]) {
+    let size = value.len();
+    if size < 1 << 6 {
+        result.push(0b1000_0000 | size as u8);
+    } else if size < 1 << 12 {
+        result.push(0b1110_0000 | (size >> 8) as u8);
+        result.push(size as u8);
+    } else {
+        result.push(0xF0);
+        result.extend_from_slice(&(size as u32).to_le_bytes());
+    }
+    result.extend_from_slice(value);
+}
+
+/// 7 bits per byte, the highest bit is set in all bytes except the one that is read last
+fn encode_back_size(result: &mut Vec<u8>, size: usize) {
+    let bytes_count = back_size_len(size);
+    for i in (0..bytes_count).rev() {
+        let byte = ((size >> (7 * i)) & 0x7F) as u8;
+        let is_last_read = i == bytes_count - 1;
+        result.push(if is_last_read { byte } else { byte | 0x80 });
+    }
+}
+
+fn back_size_len(size: usize) -> usize {
+    match size {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+pub(crate) fn decode_listpack(data: &[u8]) -> Option<Vec<ListpackValue>> {
+    if data.len() < HEADER_SIZE + 1 {
+        eprintln!("listpack is too small {}", data.len());
+        return None;
+    }
+    let total_size = u32::from_le_bytes(data[..4].try_into().ok()?) as usize;
+    if total_size != data.len() {
+        eprintln!("listpack size {total_size} does not match the size of its data {}", data.len());
+        return None;
+    }
+    let mut result = Vec::new();
+    let mut tail = &data[HEADER_SIZE..];
+    loop {
+        match tail {
+            [END_MARK] => break,
+            [] | [END_MARK, ..] => {
+                eprintln!("listpack has no end mark, or has data after it");
+                return None;
+            },
+            _ => {},
+        }
+        let (value, size) = decode_value(tail)?;
+        let next = size + back_size_len(size);
+        if tail.len() < next {
+            eprintln!("listpack element is larger than the remaining data");
+            return None;
+        }
+        result.push(value);
+        tail = &tail[next..];
+    }
+    let count = u16::from_le_bytes(data[4..HEADER_SIZE].try_into().ok()?) as usize;
+    if count != MAX_STORED_COUNT && count != result.len() {
+        eprintln!("listpack has {} elements, but its header says {count}", result.len());
+        return None;
+    }
+    Some(result)
+}
+
+/// Returns the value and the size of its encoding together with the data, without the back size
+fn decode_value(data: &[u8]) -> Option<(ListpackValue, usize)> {
+    let first = *data.first()?;
+    let int = |size: usize| -> Option<(ListpackValue, usize)> {
+        let bytes = data.get(1..1 + size)?;
+        // sign extension, by putting the bytes at the top of i64 and shifting them back
+        let mut buf = [0; 8];
+        buf[8 - size..].copy_from_slice(bytes);
+        let value = i64::from_le_bytes(buf) >> (8 * (8 - size));
+        Some((ListpackValue::Int(value), 1 + size))
+    };
+    let string = |header_size: usize, size: usize| -> Option<(ListpackValue, usize)> {
+        let value = data.get(header_size..header_size + size)?;
+        Some((ListpackValue::String(value.to_vec()), header_size + size))
+    };
+    match first {
+        x if x & 0x80 == 0 => Some((ListpackValue::Int(x.into()), 1)),
+        x if x & 0xC0 == 0x80 => string(1, (x & 0x3F).into()),
+        x if x & 0xE0 == 0xC0 => {
+            let value = u16::from_be_bytes([x & 0x1F, *data.get(1)?]) as i64;
+            // 13 bit signed integer
+            let value = if value >= 1 << 12 { value - (1 << 13) } else { value };
+            Some((ListpackValue::Int(value), 2))
+        },
+        x if x & 0xF0 == 0xE0 => string(2, u16::from_be_bytes([x & 0x0F, *data.get(1)?]).into()),
+        0xF0 => string(5, u32::from_le_bytes(data.get(1..5)?.try_into().ok()?) as usize),
+        0xF1 => int(2),
+        0xF2 => int(3),
+        0xF3 => int(4),
+        0xF4 => int(8),
+        x => {
+            eprintln!("unexpected listpack encoding {x}");
+            None
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listpack_round_trip() {
+        let values = vec![
+            ListpackValue::Int(0),
+            ListpackValue::Int(127),
+            ListpackValue::Int(-1),
+            ListpackValue::Int(1_700_000_000_000),
+            ListpackValue::Int(i64::MIN),
+            ListpackValue::String(Vec::new()),
+            ListpackValue::String(vec![b'a'; 63]),
+            ListpackValue::String(vec![b'b'; 64]),
+            ListpackValue::String(vec![b'c'; 5000]),
+            ListpackValue::String(vec![b'd'; 20000]),
+        ];
+        let encoded = encode_listpack(&values);
+        assert_eq!(decode_listpack(&encoded), Some(values));
+    }
+
+    #[test]
+    fn listpack_decodes_compact_integers() {
+        // 13 bit -2, 16 bit 1000, 24 bit -70000, and the back sizes of 2, 3 and 4 bytes
+        let mut data = vec![0, 0, 0, 0, 3, 0];
+        data.extend_from_slice(&[0xDF, 0xFE, 2]);
+        data.extend_from_slice(&[0xF1, 0xE8, 0x03, 3]);
+        data.extend_from_slice(&[0xF2, 0x90, 0xEE, 0xFE, 4]);
+        data.push(END_MARK);
+        let size = data.len() as u32;
+        data[..4].copy_from_slice(&size.to_le_bytes());
+        let expected = vec![ListpackValue::Int(-2), ListpackValue::Int(1000), ListpackValue::Int(-70000)];
+        assert_eq!(decode_listpack(&data), Some(expected));
+    }
+
+    #[test]
+    fn listpack_rejects_truncated_data() {
+        let encoded = encode_listpack(&[ListpackValue::String(b"hello".to_vec())]);
+        assert_eq!(decode_listpack(&encoded[..encoded.len() - 1]), None);
+    }
+}
@@ -2,8 +2,10 @@ use std::ffi::OsString;
 use std::path::PathBuf;
 use clap::Parser;
 use std::os::unix::ffi::OsStringExt;
+use crate::command_table::CommandNames;
 use crate::rdb::load_file;
-use crate::server::{Config, run_master, run_slave};
+use crate::resp::{DEFAULT_PROTO_MAX_BULK_LEN, DEFAULT_PROTO_MAX_MULTIBULK_LEN};
+use crate::server::{Config, DEFAULT_BACKLOG_SIZE, DEFAULT_MIN_REPLICAS_MAX_LAG, run_master, run_slave};
 
 mod resp;
 mod storage;
@@ -11,9 +13,14 @@ mod handshake;
 mod handlers;
 mod server;
 mod command;
+mod command_table;
 mod connection;
 mod rdb;
 mod transaction;
+mod backlog;
+mod output_buffer;
+mod pubsub;
+mod listpack;
 
 #[derive(Parser)]
 struct Cli {
@@ -27,6 +34,33 @@ struct Cli {
     /// the name of the RDB file
     #[arg(long)]
     dbfilename: Option<OsString>,
+    /// the size of the buffer that allows replicas to continue replication after a disconnect
+    #[arg(long, default_value_t = DEFAULT_BACKLOG_SIZE as u64, value_parser = clap::value_parser!(u64).range(1..))]
+    repl_backlog_size: u64,
+    /// the number of replicas that have to be connected for the writes to be accepted
+    #[arg(long, default_value_t = 0)]
+    min_replicas_to_write: usize,
+    /// the number of seconds since the last ack, for a replica to be counted by min-replicas-to-write
+    #[arg(long, default_value_t = DEFAULT_MIN_REPLICAS_MAX_LAG)]
+    min_replicas_max_lag: u64,
+    /// limits for the data buffered for replicas and subscribers: "<replica|pubsub> <hard> <soft> <soft seconds>", can have both classes
+    #[arg(long, default_value = "replica 256mb 64mb 60 pubsub 32mb 8mb 60")]
+    client_output_buffer_limit: String,
+    /// send the RDB file to replicas while it's being written, without knowing its size in advance
+    #[arg(long, default_value = "no", value_parser = ["yes", "no"])]
+    repl_diskless_sync: String,
+    /// the classes of keyspace events that are published to pub/sub, e.g. "KEA" or "Ex"
+    #[arg(long, default_value = "")]
+    notify_keyspace_events: String,
+    /// the max size of a single argument of a command
+    #[arg(long, default_value_t = DEFAULT_PROTO_MAX_BULK_LEN as u64, value_parser = clap::value_parser!(u64).range(1..))]
+    proto_max_bulk_len: u64,
+    /// the max number of arguments of a command
+    #[arg(long, default_value_t = DEFAULT_PROTO_MAX_MULTIBULK_LEN as u64, value_parser = clap::value_parser!(u64).range(1..))]
+    proto_max_multibulk_len: u64,
+    /// gives a command another name, or disables it if the new name is empty, can be repeated
+    #[arg(long, num_args = 2, value_names=["name", "new_name"])]
+    rename_command: Vec<String>,
 }
 
 #[tokio::main]
@@ -36,14 +70,12 @@ async fn main() {
 
     let dir = cli.dir;
     let dbfilename = cli.dbfilename;
-    let storage = if let (Some(dir), Some(file)) = (&dir, &dbfilename) {
-        let file_path = dir.join(file);
-        load_file(&file_path) 
+    let file_path = if let (Some(dir), Some(file)) = (&dir, &dbfilename) {
+        Some(dir.join(file))
     } else {
         None
     };
-    let storage = storage.unwrap_or_default();
-    
+
     let mut config = Config::default();
     if let Some(dir) = dir {
         config.insert("dir", dir.into_os_string().into_vec());
@@ -51,11 +83,29 @@ async fn main() {
     if let Some(dbfilename) = dbfilename {
         config.insert("dbfilename", dbfilename.into_vec());
     }
+    config.insert("repl-backlog-size", cli.repl_backlog_size.to_string().into_bytes());
+    config.insert("min-replicas-to-write", cli.min_replicas_to_write.to_string().into_bytes());
+    config.insert("min-replicas-max-lag", cli.min_replicas_max_lag.to_string().into_bytes());
+    config.insert("client-output-buffer-limit", cli.client_output_buffer_limit.into_bytes());
+    config.insert("repl-diskless-sync", cli.repl_diskless_sync.into_bytes());
+    config.insert("notify-keyspace-events", cli.notify_keyspace_events.into_bytes());
+    config.insert("proto-max-bulk-len", cli.proto_max_bulk_len.to_string().into_bytes());
+    config.insert("proto-max-multibulk-len", cli.proto_max_multibulk_len.to_string().into_bytes());
+
+    let renames: Vec<_> = cli.rename_command.chunks(2)
+        .map(|x| (x[0].clone(), x[1].clone()))
+        .collect();
+    let command_names = CommandNames::new(&renames);
 
-    if cli.replicaof.len() > 0 {
-        let master_addr = format!("{}:{}", cli.replicaof[0], cli.replicaof[1]);
-        run_slave(storage, port, config, &master_addr).await;
+    if !cli.replicaof.is_empty() {
+        // replica gets its data from master, so there is no need to load the file
+        let master_host = cli.replicaof[0].clone();
+        let master_port = cli.replicaof[1].parse()
+            .expect("master port should be a valid port number");
+        run_slave(port, config, command_names, master_host, master_port).await;
     } else {
-        run_master(storage, port, config).await;
+        let storage = file_path.and_then(|x| load_file(&x));
+        let storage = storage.unwrap_or_default();
+        run_master(storage, port, config, command_names).await;
     };
 }
@@ -0,0 +1,204 @@
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/*
+Each replica gets its own queue of the replication stream that was not written to it yet,
+and each pub/sub subscriber gets its own queue of the messages.
+The queue is unbounded by itself, but we keep track of how many bytes are in it,
+and a client that is too slow to keep up is disconnected, so that it can't make us run out of memory.
+After reconnecting, a replica can continue from the backlog, if it's not too far behind.
+ */
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct OutputBufferLimit {
+    /// disconnect as soon as the buffer gets larger than this, 0 means no limit
+    hard: usize,
+    /// disconnect if the buffer stays larger than this for `soft_duration`, 0 means no limit
+    soft: usize,
+    soft_duration: Duration,
+}
+impl OutputBufferLimit {
+    fn parse(hard: &str, soft: &str, soft_seconds: &str) -> Option<Self> {
+        Some(Self {
+            hard: parse_memory_size(hard)?,
+            soft: parse_memory_size(soft)?,
+            soft_duration: Duration::from_secs(soft_seconds.parse().ok()?),
+        })
+    }
+}
+
+/// The limits for each class of clients, from client-output-buffer-limit
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct OutputBufferLimits {
+    pub replica: OutputBufferLimit,
+    pub pubsub: OutputBufferLimit,
+}
+impl Default for OutputBufferLimits {
+    fn default() -> Self {
+        Self {
+            replica: OutputBufferLimit {
+                hard: 256 * 1024 * 1024,
+                soft: 64 * 1024 * 1024,
+                soft_duration: Duration::from_secs(60),
+            },
+            pubsub: OutputBufferLimit {
+                hard: 32 * 1024 * 1024,
+                soft: 8 * 1024 * 1024,
+                soft_duration: Duration::from_secs(60),
+            },
+        }
+    }
+}
+impl FromStr for OutputBufferLimits {
+    type Err = ();
+
+    /// Parses `<class> <hard> <soft> <soft seconds>` for one or more classes, the ones that are not mentioned keep the defaults.
+    /// Only the replica and the pubsub classes are supported.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<_> = s.split_ascii_whitespace().collect();
+        if parts.is_empty() || (parts.len() % 4 != 0) {
+            return Err(());
+        }
+        let mut limits = Self::default();
+        for chunk in parts.chunks(4) {
+            let [class, hard, soft, soft_seconds] = chunk else {
+                return Err(());
+            };
+            let limit = OutputBufferLimit::parse(hard, soft, soft_seconds).ok_or(())?;
+            match class.to_ascii_lowercase().as_str() {
+                "replica" | "slave" => limits.replica = limit,
+                "pubsub" => limits.pubsub = limit,
+                _ => return Err(()),
+            }
+        }
+        Ok(limits)
+    }
+}
+
+/// Parses sizes like 100, 1k, 1kb, 64mb, 1gb the same way redis does.
+fn parse_memory_size(value: &str) -> Option<usize> {
+    let value = value.to_ascii_lowercase();
+    let units = [("kb", 1024), ("k", 1000), ("mb", 1024 * 1024), ("m", 1000 * 1000), ("gb", 1024 * 1024 * 1024), ("g", 1000 * 1000 * 1000), ("b", 1)];
+    let (number, multiplier) = units.iter()
+        .find_map(|(suffix, multiplier)| value.strip_suffix(suffix).map(|x| (x, *multiplier)))
+        .unwrap_or((value.as_str(), 1));
+    let number: usize = number.parse().ok()?;
+    number.checked_mul(multiplier)
+}
+
+/// Anything that can be queued for a client, with the number of bytes that it takes in the output buffer
+pub(crate) trait BufferedItem {
+    fn buffered_size(&self) -> usize;
+}
+impl BufferedItem for Arc<[u8]> {
+    fn buffered_size(&self) -> usize {
+        self.len()
+    }
+}
+
+pub(crate) type ReplicaSender = OutputSender<Arc<[u8]>>;
+pub(crate) type ReplicaReceiver = OutputReceiver<Arc<[u8]>>;
+
+pub(crate) fn replica_channel(limit: OutputBufferLimit) -> (ReplicaSender, ReplicaReceiver) {
+    output_channel(limit)
+}
+
+pub(crate) fn output_channel<T: BufferedItem>(limit: OutputBufferLimit) -> (OutputSender<T>, OutputReceiver<T>) {
+    let (sender, receiver) = unbounded_channel();
+    let state = Arc::new(BufferState::default());
+    let sender = OutputSender { sender, limit, state: Arc::clone(&state) };
+    let receiver = OutputReceiver { receiver, state };
+    (sender, receiver)
+}
+
+/// Shared by the receiver and all the clones of the sender
+#[derive(Default)]
+struct BufferState {
+    pending_size: AtomicUsize,
+    overflowed: AtomicBool,
+    soft_exceeded_since: Mutex<Option<Instant>>,
+}
+
+#[derive(Clone)]
+pub(crate) struct OutputSender<T> {
+    sender: UnboundedSender<T>,
+    limit: OutputBufferLimit,
+    state: Arc<BufferState>,
+}
+impl<T: BufferedItem> OutputSender<T> {
+    /// Returns false if the client is gone, or if it got disconnected because it's too slow.
+    pub fn send(&self, item: T) -> bool {
+        if self.state.overflowed.load(Ordering::Relaxed) {
+            return false;
+        }
+        let size = item.buffered_size();
+        if self.sender.send(item).is_err() {
+            return false;
+        }
+        let pending_size = self.state.pending_size.fetch_add(size, Ordering::Relaxed) + size;
+        if self.is_limit_exceeded(pending_size) {
+            eprintln!("output buffer has reached {pending_size} bytes, disconnecting the client");
+            self.state.overflowed.store(true, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+    fn is_limit_exceeded(&self, pending_size: usize) -> bool {
+        if (self.limit.hard > 0) && (pending_size > self.limit.hard) {
+            return true;
+        }
+        let mut soft_exceeded_since = self.state.soft_exceeded_since.lock().expect("got poisoned lock");
+        if (self.limit.soft == 0) || (pending_size <= self.limit.soft) {
+            *soft_exceeded_since = None;
+            return false;
+        }
+        let since = *soft_exceeded_since.get_or_insert_with(Instant::now);
+        since.elapsed() > self.limit.soft_duration
+    }
+}
+
+pub(crate) enum OutputRecvError {
+    /// the client was too slow, and the data that it needs was dropped
+    Overflowed,
+    /// all the senders are gone, e.g. replication history has changed, or the server is no longer a master
+    Closed,
+}
+
+pub(crate) struct OutputReceiver<T> {
+    receiver: UnboundedReceiver<T>,
+    state: Arc<BufferState>,
+}
+impl<T: BufferedItem> OutputReceiver<T> {
+    pub async fn recv(&mut self) -> Result<T, OutputRecvError> {
+        if self.state.overflowed.load(Ordering::Relaxed) {
+            return Err(OutputRecvError::Overflowed);
+        }
+        let Some(item) = self.receiver.recv().await else {
+            return Err(OutputRecvError::Closed);
+        };
+        self.state.pending_size.fetch_sub(item.buffered_size(), Ordering::Relaxed);
+        Ok(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_buffer_limits_are_parsed_per_class() {
+        let limits: OutputBufferLimits = "pubsub 1mb 0 0".parse().unwrap();
+        assert_eq!(limits.pubsub.hard, 1024 * 1024);
+        assert_eq!(limits.pubsub.soft, 0);
+        assert_eq!(limits.replica.hard, 256 * 1024 * 1024);
+        let limits: OutputBufferLimits = "replica 1k 2k 3 pubsub 4kb 5kb 6".parse().unwrap();
+        assert_eq!(limits.replica.hard, 1000);
+        assert_eq!(limits.pubsub.soft, 5 * 1024);
+        assert_eq!(limits.pubsub.soft_duration, Duration::from_secs(6));
+        assert!("normal 0 0 0".parse::<OutputBufferLimits>().is_err());
+        assert!("pubsub 1mb 0".parse::<OutputBufferLimits>().is_err());
+    }
+}
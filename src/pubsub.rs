@@ -0,0 +1,478 @@
+use std::collections::{BTreeSet, HashMap};
+use std::str::FromStr;
+use std::sync::Arc;
+use crate::output_buffer::{output_channel, BufferedItem, OutputBufferLimit, OutputReceiver, OutputSender};
+use crate::resp::Reply;
+
+/*
+Every subscribed connection has its own queue of messages,
+the registry keeps the sending sides of the queues for every channel and pattern.
+Publishing never waits for the subscribers, it just puts the message into their queues,
+and connections deliver the messages in between reading the commands.
+A subscriber that doesn't read its messages fast enough is disconnected, once its queue goes over the pubsub output buffer limit.
+
+Shard channels are separate from the usual ones, and their messages are also sent to replicas,
+so that the subscribers of the replicas receive them too.
+ */
+
+pub(crate) type ChannelName = Vec<u8>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SubscriptionKind {
+    Channel,
+    Pattern,
+    ShardChannel,
+}
+impl SubscriptionKind {
+    const ALL: [SubscriptionKind; 3] = [SubscriptionKind::Channel, SubscriptionKind::Pattern, SubscriptionKind::ShardChannel];
+
+    pub fn subscribe_reply_name(&self) -> &'static str {
+        match self {
+            SubscriptionKind::Channel => "subscribe",
+            SubscriptionKind::Pattern => "psubscribe",
+            SubscriptionKind::ShardChannel => "ssubscribe",
+        }
+    }
+    pub fn unsubscribe_reply_name(&self) -> &'static str {
+        match self {
+            SubscriptionKind::Channel => "unsubscribe",
+            SubscriptionKind::Pattern => "punsubscribe",
+            SubscriptionKind::ShardChannel => "sunsubscribe",
+        }
+    }
+    fn message_reply_name(&self) -> &'static str {
+        match self {
+            SubscriptionKind::Channel => "message",
+            SubscriptionKind::Pattern => "pmessage",
+            SubscriptionKind::ShardChannel => "smessage",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct PubSubMessage {
+    pub kind: SubscriptionKind,
+    /// the pattern that has matched the channel, if the message is delivered because of PSUBSCRIBE
+    pub pattern: Option<ChannelName>,
+    pub channel: Arc<[u8]>,
+    pub payload: Arc<[u8]>,
+}
+impl PubSubMessage {
+    pub fn to_reply(&self) -> Reply {
+        let mut items = Vec::with_capacity(4);
+        items.push(Reply::BinaryString(self.kind.message_reply_name().as_bytes().to_vec()));
+        if let Some(pattern) = &self.pattern {
+            items.push(Reply::BinaryString(pattern.clone()));
+        }
+        items.push(Reply::BinaryString(self.channel.to_vec()));
+        items.push(Reply::BinaryString(self.payload.to_vec()));
+        Reply::Push(items)
+    }
+}
+impl BufferedItem for PubSubMessage {
+    fn buffered_size(&self) -> usize {
+        self.pattern.as_ref().map_or(0, |x| x.len()) + self.channel.len() + self.payload.len()
+    }
+}
+
+type Subscribers = HashMap<usize, OutputSender<PubSubMessage>>;
+
+pub(crate) struct PubSub {
+    output_buffer_limit: OutputBufferLimit,
+    next_id: usize,
+    channels: HashMap<ChannelName, Subscribers>,
+    patterns: HashMap<ChannelName, Subscribers>,
+    shard_channels: HashMap<ChannelName, Subscribers>,
+}
+impl PubSub {
+    pub fn new(output_buffer_limit: OutputBufferLimit) -> Self {
+        Self {
+            output_buffer_limit,
+            next_id: 0,
+            channels: Default::default(),
+            patterns: Default::default(),
+            shard_channels: Default::default(),
+        }
+    }
+    pub fn new_subscriber(&mut self) -> Subscriber {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (sender, receiver) = output_channel(self.output_buffer_limit);
+        Subscriber {
+            id,
+            sender,
+            receiver,
+            channels: Default::default(),
+            patterns: Default::default(),
+            shard_channels: Default::default(),
+        }
+    }
+    fn registry(&self, kind: SubscriptionKind) -> &HashMap<ChannelName, Subscribers> {
+        match kind {
+            SubscriptionKind::Channel => &self.channels,
+            SubscriptionKind::Pattern => &self.patterns,
+            SubscriptionKind::ShardChannel => &self.shard_channels,
+        }
+    }
+    fn registry_mut(&mut self, kind: SubscriptionKind) -> &mut HashMap<ChannelName, Subscribers> {
+        match kind {
+            SubscriptionKind::Channel => &mut self.channels,
+            SubscriptionKind::Pattern => &mut self.patterns,
+            SubscriptionKind::ShardChannel => &mut self.shard_channels,
+        }
+    }
+    pub fn subscribe(&mut self, subscriber: &mut Subscriber, kind: SubscriptionKind, channel: &[u8]) {
+        if !subscriber.subscriptions_mut(kind).insert(channel.to_vec()) {
+            return;
+        }
+        self.registry_mut(kind).entry(channel.to_vec()).or_default()
+            .insert(subscriber.id, subscriber.sender.clone());
+    }
+    pub fn unsubscribe(&mut self, subscriber: &mut Subscriber, kind: SubscriptionKind, channel: &[u8]) {
+        if !subscriber.subscriptions_mut(kind).remove(channel) {
+            return;
+        }
+        let registry = self.registry_mut(kind);
+        let Some(subscribers) = registry.get_mut(channel) else {
+            return;
+        };
+        subscribers.remove(&subscriber.id);
+        if subscribers.is_empty() {
+            registry.remove(channel);
+        }
+    }
+    pub fn unsubscribe_all(&mut self, subscriber: &mut Subscriber) {
+        for kind in SubscriptionKind::ALL {
+            let channels = std::mem::take(subscriber.subscriptions_mut(kind));
+            let registry = self.registry_mut(kind);
+            for channel in channels {
+                let Some(subscribers) = registry.get_mut(&channel) else {
+                    continue;
+                };
+                subscribers.remove(&subscriber.id);
+                if subscribers.is_empty() {
+                    registry.remove(&channel);
+                }
+            }
+        }
+    }
+    /// Returns the number of subscribers that have received the message
+    pub fn publish(&self, channel: &[u8], payload: &[u8]) -> usize {
+        let channel_shared: Arc<[u8]> = channel.into();
+        let payload: Arc<[u8]> = payload.into();
+        let mut count = 0;
+        if let Some(subscribers) = self.channels.get(channel) {
+            let message = PubSubMessage {
+                kind: SubscriptionKind::Channel,
+                pattern: None,
+                channel: channel_shared.clone(),
+                payload: payload.clone(),
+            };
+            count += send_to_all(subscribers, &message);
+        }
+        for (pattern, subscribers) in &self.patterns {
+            if !glob_match(pattern, channel) {
+                continue;
+            }
+            let message = PubSubMessage {
+                kind: SubscriptionKind::Pattern,
+                pattern: Some(pattern.clone()),
+                channel: channel_shared.clone(),
+                payload: payload.clone(),
+            };
+            count += send_to_all(subscribers, &message);
+        }
+        count
+    }
+    /// Shard channels are not matched against the patterns
+    pub fn publish_to_shard(&self, channel: &[u8], payload: &[u8]) -> usize {
+        let Some(subscribers) = self.shard_channels.get(channel) else {
+            return 0;
+        };
+        let message = PubSubMessage {
+            kind: SubscriptionKind::ShardChannel,
+            pattern: None,
+            channel: channel.into(),
+            payload: payload.into(),
+        };
+        send_to_all(subscribers, &message)
+    }
+    /// `kind` is either a usual channel or a shard channel
+    pub fn get_channels(&self, kind: SubscriptionKind, pattern: Option<&[u8]>) -> Vec<ChannelName> {
+        self.registry(kind).keys()
+            .filter(|channel| pattern.is_none_or(|pattern| glob_match(pattern, channel)))
+            .cloned()
+            .collect()
+    }
+    pub fn count_subscribers(&self, kind: SubscriptionKind, channel: &[u8]) -> usize {
+        self.registry(kind).get(channel).map_or(0, |x| x.len())
+    }
+    pub fn count_patterns(&self) -> usize {
+        self.patterns.len()
+    }
+}
+
+fn send_to_all(subscribers: &Subscribers, message: &PubSubMessage) -> usize {
+    subscribers.values()
+        .filter(|sender| sender.send(message.clone()))
+        .count()
+}
+
+/// The subscriptions of one connection, and its queue of messages
+pub(crate) struct Subscriber {
+    id: usize,
+    sender: OutputSender<PubSubMessage>,
+    pub receiver: OutputReceiver<PubSubMessage>,
+    channels: BTreeSet<ChannelName>,
+    patterns: BTreeSet<ChannelName>,
+    shard_channels: BTreeSet<ChannelName>,
+}
+impl Subscriber {
+    pub fn subscriptions(&self, kind: SubscriptionKind) -> &BTreeSet<ChannelName> {
+        match kind {
+            SubscriptionKind::Channel => &self.channels,
+            SubscriptionKind::Pattern => &self.patterns,
+            SubscriptionKind::ShardChannel => &self.shard_channels,
+        }
+    }
+    fn subscriptions_mut(&mut self, kind: SubscriptionKind) -> &mut BTreeSet<ChannelName> {
+        match kind {
+            SubscriptionKind::Channel => &mut self.channels,
+            SubscriptionKind::Pattern => &mut self.patterns,
+            SubscriptionKind::ShardChannel => &mut self.shard_channels,
+        }
+    }
+    /// The number that is reported in the replies to the (un)subscribe commands,
+    /// shard channels are counted separately from the usual ones
+    pub fn count(&self, kind: SubscriptionKind) -> usize {
+        match kind {
+            SubscriptionKind::Channel | SubscriptionKind::Pattern => self.channels.len() + self.patterns.len(),
+            SubscriptionKind::ShardChannel => self.shard_channels.len(),
+        }
+    }
+    pub fn is_subscribed(&self) -> bool {
+        !self.channels.is_empty() || !self.patterns.is_empty() || !self.shard_channels.is_empty()
+    }
+}
+
+/// Longer patterns never match anything, so that a client can't make us spend too much time on a single message
+const MAX_PATTERN_SIZE: usize = 64 * 1024;
+
+/// Glob-style matching with `*`, `?`, `[...]` and `\` escapes, the same as in redis
+pub(crate) fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    /*
+    Same as stringmatchlen in redis: when a part of the pattern does not match,
+    only the last `*` is retried with a longer match, the ones before it can't change the result.
+    So it takes at most len(pattern) * len(string) steps, and uses no recursion.
+     */
+    if pattern.len() > MAX_PATTERN_SIZE {
+        return false;
+    }
+    let mut pattern_pos = 0;
+    let mut string_pos = 0;
+    // the position after the last `*` in the pattern, and where its match ends in the string
+    let mut last_star = None;
+    loop {
+        if pattern.get(pattern_pos) == Some(&b'*') {
+            pattern_pos += 1;
+            last_star = Some((pattern_pos, string_pos));
+            continue;
+        }
+        match (pattern_pos < pattern.len(), string.get(string_pos)) {
+            (false, None) => return true,
+            (true, Some(&char)) => {
+                if let Some(size) = match_one(&pattern[pattern_pos..], char) {
+                    pattern_pos += size;
+                    string_pos += 1;
+                    continue;
+                }
+            },
+            _ => {},
+        }
+        // the star takes one more character, and the rest of the pattern is tried from there
+        match last_star {
+            Some((star_pattern_pos, star_string_pos)) if star_string_pos < string.len() => {
+                last_star = Some((star_pattern_pos, star_string_pos + 1));
+                pattern_pos = star_pattern_pos;
+                string_pos = star_string_pos + 1;
+            },
+            _ => return false,
+        }
+    }
+}
+
+/// Matches `char` against the start of `pattern`, which is not a `*`.
+/// Returns the size of the matched part of the pattern.
+fn match_one(pattern: &[u8], char: u8) -> Option<usize> {
+    let (is_match, size) = match pattern {
+        [b'?', ..] => (true, 1),
+        [b'[', tail @ ..] => {
+            let (is_match, rest) = match_class(tail, char)?;
+            (is_match, pattern.len() - rest.len())
+        },
+        [b'\\', escaped, ..] => (*escaped == char, 2),
+        [expected, ..] => (*expected == char, 1),
+        [] => (false, 0),
+    };
+    is_match.then_some(size)
+}
+
+/// Matches `char` against `[...]`, the opening bracket is already consumed.
+/// Returns the result and the rest of the pattern after the closing bracket.
+fn match_class(pattern: &[u8], char: u8) -> Option<(bool, &[u8])> {
+    let (negated, mut pattern) = match pattern.split_first() {
+        Some((b'^', tail)) => (true, tail),
+        _ => (false, pattern),
+    };
+    let mut is_match = false;
+    loop {
+        match pattern {
+            [] => return None,
+            [b']', tail @ ..] => return Some((is_match != negated, tail)),
+            [b'\\', escaped, tail @ ..] => {
+                is_match |= *escaped == char;
+                pattern = tail;
+            },
+            [start, b'-', end, tail @ ..] if *end != b']' => {
+                let (start, end) = if start <= end { (start, end) } else { (end, start) };
+                is_match |= (*start..=*end).contains(&char);
+                pattern = tail;
+            },
+            [expected, tail @ ..] => {
+                is_match |= *expected == char;
+                pattern = tail;
+            },
+        }
+    }
+}
+
+/// Kinds of keyspace events, each of them can be enabled separately in notify-keyspace-events
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum KeyspaceEventClass {
+    Generic,
+    String,
+    Stream,
+    Expired,
+    KeyMiss,
+    New,
+}
+impl KeyspaceEventClass {
+    fn flag(&self) -> u16 {
+        match self {
+            KeyspaceEventClass::Generic => KeyspaceEvents::GENERIC,
+            KeyspaceEventClass::String => KeyspaceEvents::STRING,
+            KeyspaceEventClass::Stream => KeyspaceEvents::STREAM,
+            KeyspaceEventClass::Expired => KeyspaceEvents::EXPIRED,
+            KeyspaceEventClass::KeyMiss => KeyspaceEvents::KEY_MISS,
+            KeyspaceEventClass::New => KeyspaceEvents::NEW,
+        }
+    }
+}
+
+/// The value of notify-keyspace-events, nothing is published by default
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct KeyspaceEvents(u16);
+impl KeyspaceEvents {
+    const KEYSPACE: u16 = 1 << 0;
+    const KEYEVENT: u16 = 1 << 1;
+    const GENERIC: u16 = 1 << 2;
+    const STRING: u16 = 1 << 3;
+    const LIST: u16 = 1 << 4;
+    const SET: u16 = 1 << 5;
+    const HASH: u16 = 1 << 6;
+    const SORTED_SET: u16 = 1 << 7;
+    const EXPIRED: u16 = 1 << 8;
+    const EVICTED: u16 = 1 << 9;
+    const STREAM: u16 = 1 << 10;
+    const MODULE: u16 = 1 << 11;
+    const KEY_MISS: u16 = 1 << 12;
+    const NEW: u16 = 1 << 13;
+    /// "A" doesn't include the key miss and the new key events, same as in redis
+    const ALL: u16 = Self::GENERIC | Self::STRING | Self::LIST | Self::SET | Self::HASH | Self::SORTED_SET
+        | Self::EXPIRED | Self::EVICTED | Self::STREAM | Self::MODULE;
+
+    pub fn is_keyspace_enabled(&self) -> bool {
+        self.0 & Self::KEYSPACE != 0
+    }
+    pub fn is_keyevent_enabled(&self) -> bool {
+        self.0 & Self::KEYEVENT != 0
+    }
+    pub fn is_enabled(&self, class: KeyspaceEventClass) -> bool {
+        (self.is_keyspace_enabled() || self.is_keyevent_enabled()) && (self.0 & class.flag() != 0)
+    }
+}
+impl FromStr for KeyspaceEvents {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut flags = 0;
+        for letter in s.chars() {
+            flags |= match letter {
+                'K' => Self::KEYSPACE,
+                'E' => Self::KEYEVENT,
+                'g' => Self::GENERIC,
+                '$' => Self::STRING,
+                'l' => Self::LIST,
+                's' => Self::SET,
+                'h' => Self::HASH,
+                'z' => Self::SORTED_SET,
+                'x' => Self::EXPIRED,
+                'e' => Self::EVICTED,
+                't' => Self::STREAM,
+                'd' => Self::MODULE,
+                'm' => Self::KEY_MISS,
+                'n' => Self::NEW,
+                'A' => Self::ALL,
+                _ => return Err(()),
+            };
+        }
+        Ok(Self(flags))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_match_literals_and_wildcards() {
+        assert!(glob_match(b"news.*", b"news.sport"));
+        assert!(glob_match(b"news.*", b"news."));
+        assert!(!glob_match(b"news.*", b"new"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"", b""));
+        assert!(!glob_match(b"", b"a"));
+        assert!(glob_match(b"a*b*c", b"aXXbYYbZZc"));
+        assert!(!glob_match(b"a*b*c", b"aXXbYYbZZ"));
+        assert!(glob_match(b"*a", b"aaa"));
+    }
+
+    #[test]
+    fn glob_match_classes_and_escapes() {
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-c]llo", b"hbllo"));
+        assert!(glob_match(b"h[c-a]llo", b"hbllo"));
+        assert!(!glob_match(b"h[a-c]llo", b"hdllo"));
+        assert!(glob_match(b"a\\*b", b"a*b"));
+        assert!(!glob_match(b"a\\*b", b"aXb"));
+        assert!(glob_match(b"a\\", b"a\\"));
+        assert!(!glob_match(b"h[ab", b"ha"));
+    }
+
+    #[test]
+    fn glob_match_long_patterns_are_fast() {
+        let string = vec![b'a'; 10_000];
+        let mut pattern = b"*a".repeat(50);
+        pattern.push(b'b');
+        assert!(!glob_match(&pattern, &string));
+        let pattern = vec![b'*'; 60_000];
+        assert!(glob_match(&pattern, &string));
+        let pattern = vec![b'*'; MAX_PATTERN_SIZE + 1];
+        assert!(!glob_match(&pattern, &string));
+    }
+}
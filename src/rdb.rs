@@ -1,17 +1,26 @@
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
 use std::path::PathBuf;
+use std::sync::Arc;
 use nom::branch::alt;
-use nom::bytes::complete::{tag, take};
+use nom::bytes::streaming::{tag, take};
 use nom::combinator::opt;
+use nom::multi::count;
 use nom::error::{ErrorKind, make_error, VerboseError};
-use nom::{IResult, Parser};
-use nom::multi::{many0, many_till};
-use nom::number::complete::{le_i16, le_i32, le_i8, le_u8, le_u32, le_u64};
+use nom::{IResult, Needed, Parser};
+use nom::number::streaming::{be_u32, be_u64, le_i16, le_i32, le_i8, le_u8, le_u32, le_u64};
 use nom::sequence::Tuple;
-use crate::storage::{ExpiryTs, StorageInner, StorageItem, StorageItemSimple, StorageKey};
+use crate::listpack::{decode_listpack, encode_listpack, ListpackValue};
+use crate::storage::{ExpiryTs, SimpleValue, StorageInner, StorageItem, StorageItemSimple, StorageKey, StreamEntry};
 
 const STRING_CONTROL_BITMASK: u8 = 0b11000000;
+/// the size of the pieces that the file is written in
+const DUMP_CHUNK_SIZE: usize = 64 * 1024;
+/// same as the default stream-node-max-entries in redis
+const STREAM_NODE_MAX_ENTRIES: usize = 100;
+const STREAM_ITEM_FLAG_DELETED: i64 = 1;
+const STREAM_ITEM_FLAG_SAME_FIELDS: i64 = 2;
 
 type FileParseError<I> = VerboseError<I>;
 type FileParseResult<I, O> = IResult<I, O, FileParseError<I>>;
@@ -29,38 +38,142 @@ pub(crate) fn load_file(path: &PathBuf) -> Option<StorageInner> {
         eprintln!("Failed to read file {path:?} {err}");
         return None;
     }
+    let res = parse_rdb(&contents);
+    if res.is_none() {
+        eprintln!("Failed to load file {path:?}");
+    }
+    res
+}
 
-    let (data, databases) = match parse_file(&contents) {
-        Ok(x) => x,
-        Err(err) => {
-            eprintln!("Failed to parse file {path:?} {err}");
+pub(crate) fn parse_rdb(contents: &[u8]) -> Option<StorageInner> {
+    let mut loader = RdbLoader::default();
+    loader.feed(contents)?;
+    loader.finish()
+}
+
+/*
+Loads the file piece by piece, so that it does not have to be fully read into memory first.
+The parsers tell when a piece is cut in the middle, and it's parsed again once more data arrives,
+any other error means that the data is invalid, and the loading fails right away.
+ */
+#[derive(Default)]
+pub(crate) struct RdbLoader {
+    /// the data that was received, but not parsed yet
+    buffer: Vec<u8>,
+    state: LoaderState,
+    databases_count: usize,
+    database_num: i64,
+    storage: StorageInner,
+}
+#[derive(Default, PartialEq)]
+enum LoaderState {
+    #[default]
+    Header,
+    Body,
+    Done,
+}
+enum RdbItem {
+    Auxiliary,
+    Database(i64),
+    DatabaseSize,
+    KeyValue(StorageKey, StorageItem),
+    End,
+}
+impl RdbLoader {
+    pub fn feed(&mut self, data: &[u8]) -> Option<()> {
+        self.buffer.extend_from_slice(data);
+        self.parse()
+    }
+    pub fn finish(mut self) -> Option<StorageInner> {
+        self.parse()?;
+        if self.state != LoaderState::Done {
+            eprintln!("rdb data has ended before the end of file marker");
             return None;
         }
-    };
-    if data.len() > 0 {
-        eprintln!("some data is remaining after end {}", data.len());
-        return None;
+        // for some reason codecrafters' file has one extra byte in the end
+        if self.buffer.len() > 1 {
+            eprintln!("some data is remaining after end {}", self.buffer.len());
+            return None;
+        }
+        if self.databases_count > 1 {
+            eprintln!("found more than 1 database in the file, using only the first one");
+        }
+        Some(self.storage)
     }
-    if databases.len() == 0 {
-        eprintln!("no databases found in the file");
-        return None;
-    };
-    if databases.len() > 1 {
-        eprintln!("found more than 1 database in the file, using only the first one");
+    fn parse(&mut self) -> Option<()> {
+        let mut position = 0;
+        while self.state != LoaderState::Done {
+            let data = &self.buffer[position..];
+            let res = match self.state {
+                LoaderState::Header => header(data).map(|(tail, _)| (tail, None)),
+                _ => item(data).map(|(tail, item)| (tail, Some(item))),
+            };
+            let (tail, item) = match res {
+                Ok(x) => x,
+                // the rest of the piece has not arrived yet, if it never does, `finish` reports that
+                Err(nom::Err::Incomplete(_)) => break,
+                Err(err) => {
+                    eprintln!("Failed to parse rdb {err}");
+                    return None;
+                },
+            };
+            position += data.len() - tail.len();
+            match item {
+                None => self.state = LoaderState::Body,
+                Some(item) => self.add_item(item)?,
+            }
+        }
+        self.buffer.drain(..position);
+        Some(())
+    }
+    fn add_item(&mut self, item: RdbItem) -> Option<()> {
+        match item {
+            RdbItem::Auxiliary | RdbItem::DatabaseSize => {},
+            RdbItem::Database(database_num) => {
+                self.databases_count += 1;
+                self.database_num = database_num;
+            },
+            RdbItem::KeyValue(key, item) => {
+                match self.databases_count {
+                    0 => {
+                        eprintln!("found a key before the database selector");
+                        return None;
+                    },
+                    1 => {
+                        let existing = self.storage.insert(key, Arc::new(item));
+                        if existing.is_some() {
+                            eprintln!("duplicate key found in database {}", self.database_num);
+                            return None;
+                        }
+                    },
+                    _ => {},
+                }
+            },
+            RdbItem::End => self.state = LoaderState::Done,
+        }
+        Some(())
     }
-    Some(databases[0].clone())
 }
 
-fn parse_file(data: &[u8]) -> FileParseResult<&[u8], Vec<StorageInner>> {
-    let (data, (_, _, _, (databases, _), _, _)) = (
+fn header(data: &[u8]) -> FileParseResult<&[u8], ()> {
+    let (data, _) = (
         tag(b"REDIS"),
         take(4usize), // version
-        many0(auxiliary),
-        many_till(database, tag([0xFF])),
-        le_u64, // checksum
-        opt(take(1usize)), // for some reason codecrafters' file has one extra byte in the end
     ).parse(data)?;
-    Ok((data, databases))
+    Ok((data, ()))
+}
+
+fn item(data: &[u8]) -> FileParseResult<&[u8], RdbItem> {
+    let Some(kind) = data.first() else {
+        return Err(nom::Err::Incomplete(Needed::new(1)));
+    };
+    match kind {
+        0xFA => auxiliary(data).map(|(tail, _)| (tail, RdbItem::Auxiliary)),
+        0xFE => db_selector(data).map(|(tail, x)| (tail, RdbItem::Database(x))),
+        0xFB => db_size(data).map(|(tail, _)| (tail, RdbItem::DatabaseSize)),
+        0xFF => end(data).map(|(tail, _)| (tail, RdbItem::End)),
+        _ => key_value(data).map(|(tail, (key, item))| (tail, RdbItem::KeyValue(key, item))),
+    }
 }
 
 fn auxiliary(tail: &[u8]) -> FileParseResult<&[u8], (Vec<u8>, Vec<u8>)> {
@@ -72,24 +185,15 @@ fn auxiliary(tail: &[u8]) -> FileParseResult<&[u8], (Vec<u8>, Vec<u8>)> {
     Ok((tail, (key, value)))
 }
 
-fn database(tail: &[u8]) -> FileParseResult<&[u8], StorageInner> {
-    let (tail, (database_num, _, pairs)) = (
-        db_selector,
-        db_size,
-        many0(key_value),
+fn end(tail: &[u8]) -> FileParseResult<&[u8], u64> {
+    let (tail, (_, checksum)) = (
+        tag([0xFF]),
+        le_u64,
     ).parse(tail)?;
-    let mut storage = StorageInner::default();
-    for (key, item) in pairs {
-        let existing = storage.insert(key, item);
-        if existing.is_some() {
-            eprintln!("duplicate key found in database {database_num:?}");
-            return Err(nom::Err::Error(make_error(tail, ErrorKind::Verify)));
-        }
-    }
-    Ok((tail, storage))
+    Ok((tail, checksum))
 }
 
-fn db_selector(tail: &[u8]) -> FileParseResult<&[u8], i32> {
+fn db_selector(tail: &[u8]) -> FileParseResult<&[u8], i64> {
     let (tail, (_, db_number)) = (
         tag([0xFE]),
         length_encoded_int, // database number
@@ -97,7 +201,7 @@ fn db_selector(tail: &[u8]) -> FileParseResult<&[u8], i32> {
     Ok((tail, db_number))
 }
 
-fn db_size(tail: &[u8]) -> FileParseResult<&[u8], (i32, i32)> {
+fn db_size(tail: &[u8]) -> FileParseResult<&[u8], (i64, i64)> {
     let (tail, (_, size, expiry_size)) = (
         tag([0xFB]),
         length_encoded_int, // Database hash table size
@@ -146,7 +250,8 @@ enum ValueKind {
     IntSet = 11,
     SortedSetZipList = 12,
     HashMapZipList = 13,
-    QuickList = 14
+    QuickList = 14,
+    StreamListpacks3 = 21,
 }
 impl TryFrom<u8> for ValueKind {
     type Error = ();
@@ -163,6 +268,7 @@ impl TryFrom<u8> for ValueKind {
             x if x == Self::SortedSetZipList as u8 => Self::SortedSetZipList,
             x if x == Self::HashMapZipList as u8 => Self::HashMapZipList,
             x if x == Self::QuickList as u8 => Self::QuickList,
+            x if x == Self::StreamListpacks3 as u8 => Self::StreamListpacks3,
             _ => return Err(()),
         };
         Ok(res)
@@ -184,9 +290,10 @@ fn value(tail: &[u8], kind: ValueKind, expires_at: Option<ExpiryTs>) -> FilePars
     match kind {
         ValueKind::String => length_encoded_string(tail)
             .map(|(tail, value)| (tail, StorageItem::Simple(StorageItemSimple::from_data(value, expires_at)))),
+        ValueKind::StreamListpacks3 => stream(tail),
         _ => {
             eprintln!("parsing value kind {kind:?} is not implemented yet");
-            return Err(nom::Err::Error(make_error(tail, ErrorKind::Verify)));
+            Err(nom::Err::Error(make_error(tail, ErrorKind::Verify)))
         },
     }
 }
@@ -200,16 +307,111 @@ fn length_encoded_string(tail: &[u8]) -> FileParseResult<&[u8], Vec<u8>> {
     Ok(res)
 }
 
-fn length_encoded_int(tail: &[u8]) -> FileParseResult<&[u8], i32> {
+fn length_encoded_int(tail: &[u8]) -> FileParseResult<&[u8], i64> {
     let (tail, (kind, value)) = length_encoding_control(tail)?;
     match kind {
-        0b00 => Ok((tail, value.into())),
-        0b11 => integer(tail, value),
-        _ => {
-            eprintln!("unexpected kind value for integer {kind}");
+        0b11 => integer(tail, value)
+            .map(|(tail, val)| (tail, val.into())),
+        _ => length(tail, kind, value)
+            .map(|(tail, val)| (tail, val as i64)),
+    }
+}
+
+/*
+Streams are stored as a list of nodes, each node is the id of its first entry and a listpack with the entries.
+The listpack starts with the "master" entry: the number of entries, the number of deleted ones, and the common field names.
+Each entry is its flags, the difference of its id from the node's id, its fields (unless they are the same as the master's),
+and the number of listpack elements it takes.
+ */
+fn stream(tail: &[u8]) -> FileParseResult<&[u8], StorageItem> {
+    let (mut tail, nodes_count) = length_encoded_int(tail)?;
+    let mut entries = Vec::new();
+    for _ in 0..nodes_count {
+        let (next, (master_id, listpack)) = (
+            length_encoded_string,
+            length_encoded_string,
+        ).parse(tail)?;
+        tail = next;
+        let Some(node) = stream_node(&master_id, &listpack) else {
             return Err(nom::Err::Error(make_error(tail, ErrorKind::Verify)));
+        };
+        entries.extend(node);
+    }
+    // length, last id, first id, max deleted id, the number of entries ever added
+    let (tail, _) = count(length_encoded_int, 8).parse(tail)?;
+    let (tail, groups_count) = length_encoded_int(tail)?;
+    if groups_count != 0 {
+        eprintln!("parsing of stream consumer groups is not implemented yet");
+        return Err(nom::Err::Error(make_error(tail, ErrorKind::Verify)));
+    }
+    Ok((tail, StorageItem::Stream(entries)))
+}
+
+fn stream_node(master_id: &[u8], listpack: &[u8]) -> Option<Vec<StreamEntry>> {
+    if master_id.len() != 16 {
+        eprintln!("invalid size of stream node id {}", master_id.len());
+        return None;
+    }
+    let master_ms = u64::from_be_bytes(master_id[..8].try_into().ok()?);
+    let master_seq = u64::from_be_bytes(master_id[8..].try_into().ok()?);
+    let mut values = decode_listpack(listpack)?.into_iter();
+    let values = &mut values;
+    let count = next_int(values)?;
+    let deleted = next_int(values)?;
+    let master_fields_count = next_int(values)?;
+    let master_fields = (0..master_fields_count)
+        .map(|_| next_string(values))
+        .collect::<Option<Vec<_>>>()?;
+    next_int(values)?; // the end of the master entry
+    let mut entries = Vec::new();
+    for _ in 0..count + deleted {
+        let flags = next_int(values)?;
+        let ms = master_ms.wrapping_add(next_int(values)? as u64);
+        let seq = master_seq.wrapping_add(next_int(values)? as u64);
+        let mut data = HashMap::new();
+        if flags & STREAM_ITEM_FLAG_SAME_FIELDS != 0 {
+            for field in &master_fields {
+                data.insert(field.clone(), next_string(values)?);
+            }
+        } else {
+            for _ in 0..next_int(values)? {
+                let field = next_string(values)?;
+                data.insert(field, next_string(values)?);
+            }
+        }
+        next_int(values)?; // the number of elements in the entry
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            entries.push(StreamEntry { id: format!("{ms}-{seq}").into_bytes(), data });
         }
     }
+    if values.next().is_some() {
+        eprintln!("stream node has more data than its entries");
+        return None;
+    }
+    Some(entries)
+}
+
+fn next_int(values: &mut impl Iterator<Item = ListpackValue>) -> Option<i64> {
+    let res = match values.next() {
+        Some(ListpackValue::Int(x)) => Some(x),
+        Some(ListpackValue::String(x)) => std::str::from_utf8(&x).ok().and_then(|x| x.parse().ok()),
+        None => None,
+    };
+    if res.is_none() {
+        eprintln!("expected an integer in stream node");
+    }
+    res
+}
+
+fn next_string(values: &mut impl Iterator<Item = ListpackValue>) -> Option<Vec<u8>> {
+    match values.next() {
+        Some(ListpackValue::String(x)) => Some(x),
+        Some(ListpackValue::Int(x)) => Some(x.to_string().into_bytes()),
+        None => {
+            eprintln!("stream node has ended too early");
+            None
+        },
+    }
 }
 
 fn length_encoding_control(tail: &[u8]) -> FileParseResult<&[u8], (u8, u8)> {
@@ -220,18 +422,26 @@ fn length_encoding_control(tail: &[u8]) -> FileParseResult<&[u8], (u8, u8)> {
 }
 
 fn string_normal(tail: &[u8], kind: u8, value: u8) -> FileParseResult<&[u8], Vec<u8>> {
-    let (tail, length) = match kind {
-        0b00 => (tail, value.into()),
-        0b01 => {
+    let (tail, length) = length(tail, kind, value)?;
+    let (tail, string) = take(length as usize)(tail)?;
+    Ok((tail, string.to_owned()))
+}
+
+fn length(tail: &[u8], kind: u8, value: u8) -> FileParseResult<&[u8], u64> {
+    match (kind, value) {
+        (0b00, _) => Ok((tail, value.into())),
+        (0b01, _) => {
             let (tail, next) = le_u8(tail)?;
-            let value = u16::from_le_bytes([value, next]);
-            (tail, value.into())
+            let value = u16::from_be_bytes([value, next]);
+            Ok((tail, value.into()))
         },
-        0b10 => le_u32(tail)?,
-        _ => unreachable!(),
-    };
-    let (tail, string) = take(length)(tail)?;
-    Ok((tail, string.to_owned()))
+        (0b10, 0) => be_u32(tail).map(|(tail, val)| (tail, val.into())),
+        (0b10, 1) => be_u64(tail),
+        _ => {
+            eprintln!("unexpected length encoding {kind} {value}");
+            Err(nom::Err::Error(make_error(tail, ErrorKind::Verify)))
+        },
+    }
 }
 
 fn string_special(tail: &[u8], control: u8) -> FileParseResult<&[u8], Vec<u8>> {
@@ -242,11 +452,11 @@ fn string_special(tail: &[u8], control: u8) -> FileParseResult<&[u8], Vec<u8>> {
         },
         3 => {
             eprintln!("parsing of compressed strings is not implemented yet");
-            return Err(nom::Err::Error(make_error(tail, ErrorKind::Verify)));
+            Err(nom::Err::Error(make_error(tail, ErrorKind::Verify)))
         },
         _ => {
             eprintln!("unexpected value of length-encoded string {control}");
-            return Err(nom::Err::Error(make_error(tail, ErrorKind::Verify)));
+            Err(nom::Err::Error(make_error(tail, ErrorKind::Verify)))
         },
     }
 }
@@ -265,3 +475,269 @@ fn integer(tail: &[u8], control: u8) -> FileParseResult<&[u8], i32> {
     }?;
     Ok(res)
 }
+
+/// A value that is checked and ready to be written
+enum DumpItem<'a> {
+    Simple(&'a StorageItemSimple),
+    /// entries with their ids parsed into numbers
+    Stream(Vec<((u64, u64), &'a StreamEntry)>),
+}
+
+/// The file is produced in pieces of about DUMP_CHUNK_SIZE, so it can be sent while it's being written.
+/// Returns None if some of the data can't be written in the rdb format.
+pub(crate) fn dump_chunks(storage: &StorageInner) -> Option<impl Iterator<Item = Vec<u8>> + '_> {
+    let mut header = Vec::new();
+    header.extend_from_slice(b"REDIS0011");
+    write_auxiliary(&mut header, b"redis-ver", b"7.2.0");
+    write_auxiliary(&mut header, b"redis-bits", b"64");
+
+    let mut items = Vec::new();
+    for (key, item) in storage {
+        match item.as_ref() {
+            StorageItem::Simple(x) if x.is_expired() => {},
+            StorageItem::Simple(x) => items.push((key, DumpItem::Simple(x))),
+            StorageItem::Stream(x) => {
+                // a snapshot without some of the keys would make the replica silently differ from master
+                let entries = x.iter()
+                    .map(|entry| Some((parse_stream_id(&entry.id)?, entry)))
+                    .collect::<Option<Vec<_>>>();
+                let Some(entries) = entries else {
+                    eprintln!("stream {:?} has ids that can't be written to rdb", String::from_utf8_lossy(key));
+                    return None;
+                };
+                items.push((key, DumpItem::Stream(entries)));
+            },
+        }
+    }
+    // empty databases are skipped, same as redis does
+    if !items.is_empty() {
+        let expiry_size = items.iter()
+            .filter(|(_, x)| matches!(x, DumpItem::Simple(x) if x.expires_at.is_some()))
+            .count();
+        header.push(0xFE); // database selector
+        write_length(&mut header, 0);
+        header.push(0xFB); // hash table sizes
+        write_length(&mut header, items.len());
+        write_length(&mut header, expiry_size);
+    }
+
+    let mut header = Some(header);
+    let mut items = items.into_iter();
+    let mut is_done = false;
+    let chunks = std::iter::from_fn(move || {
+        if is_done {
+            return None;
+        }
+        let mut result = header.take().unwrap_or_default();
+        for (key, item) in items.by_ref() {
+            match item {
+                DumpItem::Simple(item) => {
+                    if let Some(expires_at) = item.expires_at {
+                        result.push(0xFC);
+                        result.extend_from_slice(&(expires_at as u64).to_le_bytes());
+                    }
+                    result.push(ValueKind::String as u8);
+                    write_string(&mut result, key);
+                    match &item.value {
+                        SimpleValue::String(x) => write_string(&mut result, x),
+                        SimpleValue::Int(x) => write_string(&mut result, x.to_string().as_bytes()),
+                    }
+                },
+                DumpItem::Stream(entries) => {
+                    result.push(ValueKind::StreamListpacks3 as u8);
+                    write_string(&mut result, key);
+                    write_stream(&mut result, &entries);
+                },
+            }
+            if result.len() >= DUMP_CHUNK_SIZE {
+                return Some(result);
+            }
+        }
+        result.push(0xFF);
+        // a zero checksum tells the loader that the checksum was not calculated
+        result.extend_from_slice(&0u64.to_le_bytes());
+        is_done = true;
+        Some(result)
+    });
+    Some(chunks)
+}
+
+/// Only the ids in the "<ms>-<seq>" form can be stored
+fn parse_stream_id(id: &[u8]) -> Option<(u64, u64)> {
+    let id = std::str::from_utf8(id).ok()?;
+    let (ms, seq) = id.split_once('-')?;
+    let parsed = (ms.parse().ok()?, seq.parse().ok()?);
+    // e.g. "01-1" would be loaded back as "1-1"
+    (format!("{}-{}", parsed.0, parsed.1) == id).then_some(parsed)
+}
+
+fn write_stream(result: &mut Vec<u8>, entries: &[((u64, u64), &StreamEntry)]) {
+    let nodes = entries.chunks(STREAM_NODE_MAX_ENTRIES);
+    write_length(result, nodes.len());
+    for node in nodes {
+        let (master_ms, master_seq) = node[0].0;
+        let mut master_id = master_ms.to_be_bytes().to_vec();
+        master_id.extend_from_slice(&master_seq.to_be_bytes());
+        write_string(result, &master_id);
+        // the master entry has no common fields, so each entry lists its own ones
+        let mut values = vec![
+            ListpackValue::Int(node.len() as i64),
+            ListpackValue::Int(0),
+            ListpackValue::Int(0),
+            ListpackValue::Int(0),
+        ];
+        for ((ms, seq), entry) in node {
+            values.push(ListpackValue::Int(0)); // flags
+            values.push(ListpackValue::Int(ms.wrapping_sub(master_ms) as i64));
+            values.push(ListpackValue::Int(seq.wrapping_sub(master_seq) as i64));
+            values.push(ListpackValue::Int(entry.data.len() as i64));
+            for (field, value) in &entry.data {
+                values.push(ListpackValue::String(field.clone()));
+                values.push(ListpackValue::String(value.clone()));
+            }
+            // flags, ids, the number of fields, the fields and the values
+            values.push(ListpackValue::Int(4 + 2 * entry.data.len() as i64));
+        }
+        write_string(result, &encode_listpack(&values));
+    }
+    let first_id = entries.first().map_or((0, 0), |x| x.0);
+    let last_id = entries.last().map_or((0, 0), |x| x.0);
+    write_length(result, entries.len());
+    write_length(result, last_id.0 as usize);
+    write_length(result, last_id.1 as usize);
+    write_length(result, first_id.0 as usize);
+    write_length(result, first_id.1 as usize);
+    // the max deleted id
+    write_length(result, 0);
+    write_length(result, 0);
+    // the number of entries that were ever added
+    write_length(result, entries.len());
+    // consumer groups
+    write_length(result, 0);
+}
+
+fn write_auxiliary(result: &mut Vec<u8>, key: &[u8], value: &[u8]) {
+    result.push(0xFA);
+    write_string(result, key);
+    write_string(result, value);
+}
+
+fn write_string(result: &mut Vec<u8>, string: &[u8]) {
+    write_length(result, string.len());
+    result.extend_from_slice(string);
+}
+
+fn write_length(result: &mut Vec<u8>, length: usize) {
+    if length < 1 << 6 {
+        result.push(length as u8);
+    } else if length < 1 << 14 {
+        let bytes = (length as u16).to_be_bytes();
+        result.push(bytes[0] | 0b01 << 6);
+        result.push(bytes[1]);
+    } else if length <= u32::MAX as usize {
+        result.push(0b10 << 6);
+        result.extend_from_slice(&(length as u32).to_be_bytes());
+    } else {
+        result.push(0b10 << 6 | 1);
+        result.extend_from_slice(&(length as u64).to_be_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::now_ts;
+
+    fn dump(storage: &StorageInner) -> Option<Vec<u8>> {
+        Some(dump_chunks(storage)?.collect::<Vec<_>>().concat())
+    }
+
+    fn simple(storage: &StorageInner, key: &[u8]) -> StorageItemSimple {
+        match storage.get(key).map(|x| x.as_ref()) {
+            Some(StorageItem::Simple(x)) => x.clone(),
+            x => panic!("expected a simple value, got {x:?}"),
+        }
+    }
+
+    fn stream_entry(id: &str, data: &[(&str, &str)]) -> StreamEntry {
+        let data = data.iter().map(|(k, v)| (k.as_bytes().to_vec(), v.as_bytes().to_vec())).collect();
+        StreamEntry { id: id.as_bytes().to_vec(), data }
+    }
+
+    #[test]
+    fn dump_round_trip_simple_values() {
+        let expires_at = now_ts() + 60_000;
+        let mut storage = StorageInner::new();
+        storage.insert(b"str".to_vec(), Arc::new(StorageItem::Simple(StorageItemSimple::from_data(b"hello".to_vec(), None))));
+        storage.insert(b"int".to_vec(), Arc::new(StorageItem::Simple(StorageItemSimple::from_data(b"-42".to_vec(), None))));
+        storage.insert(b"big".to_vec(), Arc::new(StorageItem::Simple(StorageItemSimple::from_data(vec![b'x'; 100_000], None))));
+        storage.insert(b"ttl".to_vec(), Arc::new(StorageItem::Simple(StorageItemSimple::from_data(b"v".to_vec(), Some(expires_at)))));
+        storage.insert(b"old".to_vec(), Arc::new(StorageItem::Simple(StorageItemSimple::from_data(b"v".to_vec(), Some(1)))));
+
+        let loaded = parse_rdb(&dump(&storage).unwrap()).unwrap();
+        assert_eq!(loaded.len(), 4);
+        assert!(matches!(simple(&loaded, b"str").value, SimpleValue::String(x) if x == b"hello"));
+        assert!(matches!(simple(&loaded, b"int").value, SimpleValue::Int(-42)));
+        assert!(matches!(simple(&loaded, b"big").value, SimpleValue::String(x) if x == vec![b'x'; 100_000]));
+        assert_eq!(simple(&loaded, b"ttl").expires_at, Some(expires_at));
+        assert_eq!(simple(&loaded, b"str").expires_at, None);
+    }
+
+    #[test]
+    fn dump_round_trip_streams() {
+        // more entries than fit into one node, with ids that are far apart
+        let mut entries: Vec<_> = (0..250)
+            .map(|i| stream_entry(&format!("{}-{}", 1_700_000_000_000u64 + i / 3, i % 3), &[("n", &i.to_string())]))
+            .collect();
+        entries.push(stream_entry("18446744073709551615-0", &[("a", "1"), ("b", ""), ("long", &"z".repeat(5000))]));
+        let mut storage = StorageInner::new();
+        storage.insert(b"s".to_vec(), Arc::new(StorageItem::Stream(entries.clone())));
+        storage.insert(b"empty".to_vec(), Arc::new(StorageItem::Stream(Vec::new())));
+
+        let loaded = parse_rdb(&dump(&storage).unwrap()).unwrap();
+        let Some(StorageItem::Stream(loaded_entries)) = loaded.get(b"s".as_slice()).map(|x| x.as_ref()) else {
+            panic!("stream was not loaded");
+        };
+        assert_eq!(loaded_entries.len(), entries.len());
+        for (loaded, expected) in loaded_entries.iter().zip(&entries) {
+            assert_eq!(loaded.id, expected.id);
+            assert_eq!(loaded.data, expected.data);
+        }
+        assert!(matches!(loaded.get(b"empty".as_slice()).map(|x| x.as_ref()), Some(StorageItem::Stream(x)) if x.is_empty()));
+    }
+
+    #[test]
+    fn dump_refuses_unsupported_stream_ids() {
+        let mut storage = StorageInner::new();
+        storage.insert(b"s".to_vec(), Arc::new(StorageItem::Stream(vec![stream_entry("01-1", &[("a", "1")])])));
+        assert!(dump(&storage).is_none());
+        storage.insert(b"s".to_vec(), Arc::new(StorageItem::Stream(vec![stream_entry("abc", &[("a", "1")])])));
+        assert!(dump_chunks(&storage).is_none());
+    }
+
+    #[test]
+    fn loader_waits_for_pieces_that_are_cut() {
+        let mut storage = StorageInner::new();
+        storage.insert(b"key".to_vec(), Arc::new(StorageItem::Simple(StorageItemSimple::from_data(b"value".to_vec(), None))));
+        let data = dump(&storage).unwrap();
+        let mut loader = RdbLoader::default();
+        for byte in &data {
+            assert!(loader.feed(std::slice::from_ref(byte)).is_some());
+        }
+        let loaded = loader.finish().unwrap();
+        assert!(matches!(simple(&loaded, b"key").value, SimpleValue::String(x) if x == b"value"));
+
+        let mut loader = RdbLoader::default();
+        loader.feed(&data[..data.len() - 3]).unwrap();
+        assert!(loader.finish().is_none());
+    }
+
+    #[test]
+    fn loader_fails_on_invalid_data_right_away() {
+        let mut loader = RdbLoader::default();
+        // an unsupported value kind, followed by the start of a key
+        assert!(loader.feed(b"REDIS0011\xfe\x00\x07\x03k").is_none());
+        let mut loader = RdbLoader::default();
+        assert!(loader.feed(b"REDIS0011\xfe\x00\x00\xc3").is_none());
+    }
+}
@@ -1,108 +1,374 @@
 use std::future::Future;
+use std::io::Result as IoResult;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 use std::time::Duration;
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, ReadBuf};
 use tokio::time::timeout;
 use crate::command::{Command, CommandRaw};
 
 const DELIMITER_STR: &str = "\r\n";
 const DELIMITER_BYTES: &[u8] = DELIMITER_STR.as_bytes();
+const MAX_INLINE_SIZE: usize = 64 * 1024;
+/// long enough for any integer that fits into usize
+const MAX_INT_LINE_SIZE: u64 = 32;
+/// the sizes are sent by the client, so larger buffers only grow as the data actually arrives
+const MAX_PREALLOCATED_SIZE: usize = 16 * 1024;
+pub(crate) const DEFAULT_PROTO_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+pub(crate) const DEFAULT_PROTO_MAX_MULTIBULK_LEN: usize = 1024 * 1024;
+pub(crate) const EOF_MARK_SIZE: usize = 40;
 
-pub(crate) async fn read_command(reader: &mut (impl AsyncBufReadExt + Unpin)) -> Option<CommandRaw> {
+/// The largest commands that clients are allowed to send.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct ProtocolLimits {
+    /// the max size of a single argument
+    pub max_bulk_len: usize,
+    /// the max number of arguments, including the command name
+    pub max_multibulk_len: usize,
+}
+
+/// Why a command could not be read.
+#[derive(Debug)]
+pub(crate) enum ReadError {
+    /// the connection was closed, has failed or timed out, so there is no one to reply to
+    Closed,
+    /// the client has sent something that is not a command, it's told about that before the connection is closed
+    Protocol(String),
+}
+
+pub(crate) async fn read_command(reader: &mut (impl AsyncBufReadExt + Unpin), limits: ProtocolLimits) -> Result<CommandRaw, ReadError> {
     /*
     Only the very first read does not have a timeout.
     Because we are reading only a very small amounts of data,
     and because pauses between messages are expected.
-    Later reads have a timeout, to protect against clients that would make us allocate memory and hold it
+    Every later read has its own timeout, to protect against clients that would make us allocate memory and hold it
     and because pauses there are not expected.
+    The timeout is not for the whole command, so that large arguments can arrive slowly, as long as they keep arriving.
      */
-    let (array_size, mut read_bytes) = read_command_array_size(reader).await?;
-    exec_with_timeout(async move {
-        let mut command = Vec::with_capacity(array_size);
-        for _ in 0..array_size {
-            let (param, param_bytes) = read_binary_string_with_size(reader, true).await?;
-            read_bytes += param_bytes;
-            command.push(param)
+    loop {
+        let is_multibulk = match reader.fill_buf().await {
+            Ok([]) => return Err(ReadError::Closed),
+            Ok(buf) => buf[0] == b'*',
+            Err(err) => {
+                eprintln!("failed to read command {err}");
+                return Err(ReadError::Closed);
+            }
+        };
+        let command = if is_multibulk {
+            read_multibulk_command(reader, limits).await?
+        } else {
+            read_inline_command(reader, limits).await?
+        };
+        // empty commands are ignored, just like in redis
+        if !command.is_empty() {
+            return Ok(command);
         }
-        Some((command, read_bytes))
-    }).await
+    }
 }
 
-async fn read_command_array_size(reader: &mut (impl AsyncBufReadExt + Unpin)) -> Option<(usize, usize)> {
-    return read_int(reader, "*", true, 100).await
+async fn read_multibulk_command(reader: &mut (impl AsyncBufReadExt + Unpin), limits: ProtocolLimits) -> Result<CommandRaw, ReadError> {
+    let array_size = read_command_array_size(reader, limits.max_multibulk_len).await?;
+    let mut command = Vec::with_capacity(array_size.min(MAX_PREALLOCATED_SIZE));
+    for _ in 0..array_size {
+        let param = do_read_binary_string(reader, true, limits.max_bulk_len).await?;
+        command.push(param)
+    }
+    Ok(command)
 }
 
-async fn read_int(reader: &mut (impl AsyncBufReadExt + Unpin), expected_type_prefix: &'static str, is_eof_expected: bool, max: usize) -> Option<(usize, usize)> {
-    let mut buf = String::new();
-    let res = reader.take(10).read_line(&mut buf).await;
-    if let Err(err) = res {
-        eprintln!("failed to read integer line {err}");
-        return None;
-    }
-    let read_bytes = buf.len();
-    if read_bytes == 0 {
-        if !is_eof_expected {
-            eprintln!("unexpected end of file when reading integer");
+/// Reads a command typed by a human, e.g. with telnet: arguments are separated by spaces and can be quoted.
+async fn read_inline_command(reader: &mut (impl AsyncBufReadExt + Unpin), limits: ProtocolLimits) -> Result<CommandRaw, ReadError> {
+    let mut line = Vec::new();
+    // the first byte has already arrived, so the rest of the line is read with a timeout, same as the other lines
+    let read_line = async {
+        reader.take(MAX_INLINE_SIZE as u64 + 1).read_until(b'\n', &mut line).await
+            .map_err(|err| {
+                eprintln!("failed to read inline command {err}");
+                ReadError::Closed
+            })
+    };
+    read_with_timeout(read_line).await?;
+    let Some(line) = line.strip_suffix(b"\n") else {
+        if line.len() > MAX_INLINE_SIZE {
+            return Err(ReadError::Protocol("too big inline request".to_string()));
         }
-        // EOF
-        return None;
-    }
-    let Some(buf) = buf.strip_prefix(expected_type_prefix) else {
-        eprintln!("invalid format, did not find prefix {expected_type_prefix} in {buf}");
-        return None;
+        eprintln!("unexpected end of file when reading inline command");
+        return Err(ReadError::Closed);
     };
-    let Some(buf) = buf.strip_suffix(DELIMITER_STR) else {
-        eprintln!("invalid format, delimiter is missing or string is too large {buf}");
-        return None;
+    let line = line.strip_suffix(b"\r").unwrap_or(line);
+    let Some(args) = split_args(line) else {
+        return Err(ReadError::Protocol("unbalanced quotes in request".to_string()));
     };
-    let int = match buf.parse() {
-        Ok(x) => x,
-        Err(err) => {
-            eprintln!("failed to parse integer from {buf}: {err}");
-            return None;
+    if args.len() > limits.max_multibulk_len {
+        return Err(ReadError::Protocol("invalid multibulk length".to_string()));
+    }
+    if args.iter().any(|x| x.len() > limits.max_bulk_len) {
+        return Err(ReadError::Protocol("invalid bulk length".to_string()));
+    }
+    Ok(args)
+}
+
+/// Splits a line into arguments the same way redis-cli does.
+/// Returns None if the quotes are unbalanced, or a closing quote is not followed by a space.
+fn split_args(line: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut args = Vec::new();
+    let mut i = 0;
+    loop {
+        while i < line.len() && line[i].is_ascii_whitespace() {
+            i += 1;
         }
-    };
-    if int > max {
-        eprintln!("integer {int} larger than max allowed {max}");
-        return None;
+        if i == line.len() {
+            return Some(args);
+        }
+        let mut arg = Vec::new();
+        let mut in_double_quotes = false;
+        let mut in_single_quotes = false;
+        loop {
+            let Some(&c) = line.get(i) else {
+                if in_double_quotes || in_single_quotes {
+                    return None;
+                }
+                break;
+            };
+            let next = line.get(i + 1).copied();
+            if in_double_quotes {
+                match (c, next) {
+                    (b'\\', Some(b'x')) if line.len() > i + 3 && line[i + 2].is_ascii_hexdigit() && line[i + 3].is_ascii_hexdigit() => {
+                        let hex = std::str::from_utf8(&line[i + 2..i + 4]).ok()?;
+                        arg.push(u8::from_str_radix(hex, 16).ok()?);
+                        i += 3;
+                    },
+                    (b'\\', Some(escaped)) => {
+                        arg.push(match escaped {
+                            b'n' => b'\n',
+                            b'r' => b'\r',
+                            b't' => b'\t',
+                            b'b' => 0x08,
+                            b'a' => 0x07,
+                            x => x,
+                        });
+                        i += 1;
+                    },
+                    (b'"', next) => {
+                        // the closing quote must be followed by a space or nothing
+                        if next.is_some_and(|x| !x.is_ascii_whitespace()) {
+                            return None;
+                        }
+                        i += 1;
+                        break;
+                    },
+                    (c, _) => arg.push(c),
+                }
+            } else if in_single_quotes {
+                match (c, next) {
+                    (b'\\', Some(b'\'')) => {
+                        arg.push(b'\'');
+                        i += 1;
+                    },
+                    (b'\'', next) => {
+                        if next.is_some_and(|x| !x.is_ascii_whitespace()) {
+                            return None;
+                        }
+                        i += 1;
+                        break;
+                    },
+                    (c, _) => arg.push(c),
+                }
+            } else {
+                match c {
+                    c if c.is_ascii_whitespace() => break,
+                    b'"' => in_double_quotes = true,
+                    b'\'' => in_single_quotes = true,
+                    c => arg.push(c),
+                }
+            }
+            i += 1;
+        }
+        args.push(arg);
     }
-    Some((int, read_bytes))
 }
 
-pub(crate) async fn read_binary_string(reader: &mut (impl AsyncBufReadExt + Unpin), with_delimiter: bool) -> Option<Vec<u8>> {
-    exec_with_timeout(
-        read_binary_string_with_size(reader, with_delimiter)
-    ).await.map(|x| x.0)
+/// Same as read_command, but also returns the exact bytes that the command was read from.
+pub(crate) async fn read_command_with_bytes<R: AsyncRead + Unpin>(reader: &mut BufReader<R>, limits: ProtocolLimits) -> Result<(CommandRaw, Vec<u8>), ReadError> {
+    let mut recorder = Recorder { reader, bytes: Vec::new() };
+    let command = read_command(&mut recorder, limits).await?;
+    Ok((command, recorder.bytes))
+}
+
+/// Remembers all the bytes that were consumed from the reader.
+struct Recorder<'a, R> {
+    reader: &'a mut BufReader<R>,
+    bytes: Vec<u8>,
+}
+impl<R: AsyncRead + Unpin> AsyncRead for Recorder<'_, R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<IoResult<()>> {
+        let this = self.get_mut();
+        let filled_before = buf.filled().len();
+        let result = Pin::new(&mut *this.reader).poll_read(cx, buf);
+        this.bytes.extend_from_slice(&buf.filled()[filled_before..]);
+        result
+    }
+}
+impl<R: AsyncRead + Unpin> AsyncBufRead for Recorder<'_, R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<&[u8]>> {
+        Pin::new(&mut *self.get_mut().reader).poll_fill_buf(cx)
+    }
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        this.bytes.extend_from_slice(&this.reader.buffer()[..amt]);
+        Pin::new(&mut *this.reader).consume(amt);
+    }
+}
+
+async fn read_command_array_size(reader: &mut (impl AsyncBufReadExt + Unpin), max_size: usize) -> Result<usize, ReadError> {
+    read_int(reader, b'*', "invalid multibulk length", max_size).await
+}
+
+/// `error` is the protocol error that is reported when the integer is malformed or is too large.
+async fn read_int(reader: &mut (impl AsyncBufReadExt + Unpin), expected_type_prefix: u8, error: &'static str, max: usize) -> Result<usize, ReadError> {
+    let mut buf = Vec::new();
+    let res = reader.take(MAX_INT_LINE_SIZE).read_until(b'\n', &mut buf).await;
+    if let Err(err) = res {
+        eprintln!("failed to read integer line {err}");
+        return Err(ReadError::Closed);
+    }
+    if !buf.ends_with(b"\n") && buf.len() < MAX_INT_LINE_SIZE as usize {
+        eprintln!("unexpected end of file when reading integer");
+        return Err(ReadError::Closed);
+    }
+    let Some(buf) = buf.strip_prefix(&[expected_type_prefix]) else {
+        let got = buf.first().copied().unwrap_or_default() as char;
+        return Err(ReadError::Protocol(format!("expected '{}', got '{got}'", expected_type_prefix as char)));
+    };
+    let int = buf.strip_suffix(DELIMITER_BYTES)
+        .and_then(|x| std::str::from_utf8(x).ok())
+        .and_then(|x| x.parse::<usize>().ok());
+    match int {
+        Some(int) if int <= max => Ok(int),
+        _ => Err(ReadError::Protocol(error.to_string())),
+    }
 }
 
-async fn read_binary_string_with_size(reader: &mut (impl AsyncBufReadExt + Unpin), with_delimiter: bool) -> Option<(Vec<u8>, usize)> {
-    let (size, size_bytes) = read_binary_string_size(reader).await?;
-    let (string, string_bytes) = read_binary_string_body(reader, size, with_delimiter).await?;
-    Some((string, size_bytes + string_bytes))
+async fn do_read_binary_string(reader: &mut (impl AsyncBufReadExt + Unpin), with_delimiter: bool, max_size: usize) -> Result<Vec<u8>, ReadError> {
+    let size = read_with_timeout(read_binary_string_size(reader, max_size)).await?;
+    read_binary_string_body(reader, size, with_delimiter).await
 }
 
-async fn read_binary_string_size(reader: &mut (impl AsyncBufReadExt + Unpin)) -> Option<(usize, usize)> {
-    return read_int(reader, "$", false, 300).await
+async fn read_binary_string_size(reader: &mut (impl AsyncBufReadExt + Unpin), max_size: usize) -> Result<usize, ReadError> {
+    read_int(reader, b'$', "invalid bulk length", max_size).await
 }
 
-async fn read_binary_string_body(reader: &mut (impl AsyncBufReadExt + Unpin), expected_size: usize, with_delimiter: bool) -> Option<(Vec<u8>, usize)> {
+async fn read_binary_string_body(reader: &mut (impl AsyncBufReadExt + Unpin), expected_size: usize, with_delimiter: bool) -> Result<Vec<u8>, ReadError> {
     let mut buffer_size = expected_size;
     if with_delimiter {
         buffer_size += DELIMITER_BYTES.len();
     }
-    let mut result = vec![0; buffer_size];
-    let res = reader.read_exact(&mut result).await;
+    let mut result = Vec::with_capacity(buffer_size.min(MAX_PREALLOCATED_SIZE));
+    read_chunks_with_length(reader, buffer_size, |chunk| {
+        result.extend_from_slice(chunk);
+        Some(())
+    }).await.ok_or(ReadError::Closed)?;
+    if with_delimiter {
+        if !result.ends_with(DELIMITER_BYTES) {
+            return Err(ReadError::Protocol("bulk string is not followed by CRLF".to_string()));
+        }
+        result.truncate(result.len() - DELIMITER_BYTES.len());
+    }
+    Ok(result)
+}
+
+/// The size of a binary string, or the mark that it ends with, when the size was not known in advance.
+enum BinaryStringSize {
+    Length(usize),
+    EofMark(Vec<u8>),
+}
+
+/// Reads a binary string without a delimiter, and passes it to `on_chunk` piece by piece, as it arrives.
+pub(crate) async fn read_binary_string_chunks(reader: &mut (impl AsyncBufReadExt + Unpin), on_chunk: impl FnMut(&[u8]) -> Option<()>) -> Option<()> {
+    let size = exec_with_timeout(read_binary_string_size_or_mark(reader)).await?;
+    match size {
+        BinaryStringSize::Length(size) => read_chunks_with_length(reader, size, on_chunk).await,
+        BinaryStringSize::EofMark(mark) => read_chunks_until_mark(reader, &mark, on_chunk).await,
+    }
+}
+
+async fn read_binary_string_size_or_mark(reader: &mut (impl AsyncBufReadExt + Unpin)) -> Option<BinaryStringSize> {
+    let mut buf = Vec::new();
+    let res = reader.take(100).read_until(b'\n', &mut buf).await;
     if let Err(err) = res {
-        eprintln!("failed to read string line {err}");
+        eprintln!("failed to read binary string size {err}");
         return None;
     }
-    if with_delimiter {
-        if !result.ends_with(DELIMITER_BYTES) {
-            eprintln!("invalid format, string is missing the delimiter");
+    let Some(buf) = buf.strip_prefix(b"$").and_then(|x| x.strip_suffix(DELIMITER_BYTES)) else {
+        eprintln!("invalid format of binary string size {:?}", String::from_utf8_lossy(&buf));
+        return None;
+    };
+    if let Some(mark) = buf.strip_prefix(b"EOF:") {
+        if mark.len() != EOF_MARK_SIZE {
+            eprintln!("invalid length of the eof mark {}", mark.len());
             return None;
         }
-        result.truncate(result.len() - DELIMITER_BYTES.len());
+        return Some(BinaryStringSize::EofMark(mark.to_vec()));
+    }
+    let size = std::str::from_utf8(buf).ok().and_then(|x| x.parse::<usize>().ok());
+    let Some(size) = size else {
+        eprintln!("failed to parse binary string size {:?}", String::from_utf8_lossy(buf));
+        return None;
+    };
+    Some(BinaryStringSize::Length(size))
+}
+
+async fn read_chunks_with_length(reader: &mut (impl AsyncBufReadExt + Unpin), size: usize, mut on_chunk: impl FnMut(&[u8]) -> Option<()>) -> Option<()> {
+    let mut remaining = size;
+    while remaining > 0 {
+        let chunk = fill_buf_with_timeout(reader).await?;
+        let chunk_size = chunk.len().min(remaining);
+        on_chunk(&chunk[..chunk_size])?;
+        reader.consume(chunk_size);
+        remaining -= chunk_size;
+    }
+    Some(())
+}
+
+async fn read_chunks_until_mark(reader: &mut (impl AsyncBufReadExt + Unpin), mark: &[u8], mut on_chunk: impl FnMut(&[u8]) -> Option<()>) -> Option<()> {
+    /*
+    The data is followed by the replication stream, so we can't read past the mark.
+    The end of what we've read so far can be the beginning of the mark, so it's held back until more data arrives.
+     */
+    let mut pending = Vec::new();
+    loop {
+        let chunk = fill_buf_with_timeout(reader).await?;
+        let chunk_size = chunk.len();
+        pending.extend_from_slice(chunk);
+        if let Some(position) = pending.windows(mark.len()).position(|x| x == mark) {
+            let after_mark = pending.len() - position - mark.len();
+            reader.consume(chunk_size - after_mark);
+            return on_chunk(&pending[..position]);
+        }
+        reader.consume(chunk_size);
+        let ready_size = pending.len().saturating_sub(mark.len() - 1);
+        on_chunk(&pending[..ready_size])?;
+        pending.drain(..ready_size);
+    }
+}
+
+async fn fill_buf_with_timeout<R: AsyncBufReadExt + Unpin>(reader: &mut R) -> Option<&[u8]> {
+    let res = timeout(Duration::from_millis(1000), reader.fill_buf()).await;
+    match res {
+        Ok(Ok([])) => {
+            eprintln!("unexpected end of file when reading binary string");
+            None
+        },
+        Ok(Ok(x)) => Some(x),
+        Ok(Err(err)) => {
+            eprintln!("failed to read binary string {err}");
+            None
+        },
+        Err(_) => {
+            eprintln!("operation timed out");
+            None
+        },
     }
-    Some((result, buffer_size))
 }
 
 pub(crate) async fn read_simple_string(reader: &mut (impl AsyncBufReadExt + Unpin), max_size: u64) -> Option<String> {
@@ -113,7 +379,7 @@ pub(crate) async fn read_simple_string(reader: &mut (impl AsyncBufReadExt + Unpi
             eprintln!("Failed to read simple string {error}");
             return None;
         }
-        if buf.len() == 0 {
+        if buf.is_empty() {
             eprintln!("Got EOF when reading a simple string");
             return None;
         }
@@ -141,26 +407,6 @@ pub(crate) async fn write_simple_string(stream: &mut (impl AsyncWriteExt + Unpin
     }).await
 }
 
-pub(crate) async fn write_simple_error(stream: &mut (impl AsyncWriteExt + Unpin), string: impl AsRef<str>) -> Option<()> {
-    exec_with_timeout(async move {
-        let string = string.as_ref();
-        let result = stream.write_all(format!("-{string}{DELIMITER_STR}").as_bytes()).await;
-        if let Err(error) = result {
-            eprintln!("failed to write simple error: {error}");
-            return None;
-        }
-        Some(())
-    }).await
-}
-
-#[allow(dead_code)]
-pub(crate) async fn write_binary_string_or_null(stream: &mut (impl AsyncWriteExt + Unpin), string: Option<impl AsRef<[u8]>>) -> Option<()> {
-    match string {
-        Some(value) => write_binary_string(stream, &value, true).await,
-        None => write_null(stream).await,
-    }
-}
-
 pub(crate) async fn write_binary_string(stream: &mut (impl AsyncWriteExt + Unpin), string: impl AsRef<[u8]>, with_delimiter: bool) -> Option<()> {
     exec_with_timeout(async move {
         let string = string.as_ref();
@@ -185,26 +431,14 @@ pub(crate) async fn write_binary_string(stream: &mut (impl AsyncWriteExt + Unpin
     }).await
 }
 
-pub(crate) async fn write_null(stream: &mut (impl AsyncWriteExt + Unpin)) -> Option<()> {
-    exec_with_timeout(async move {
-        let result = stream.write_all(format!("$-1{DELIMITER_STR}").as_bytes()).await;
-        if let Err(error) = result {
-            eprintln!("failed to write simple string: {error}");
-            return None;
-        }
-        Some(())
-    }).await
+/// Only the size, so that the body can be written in several parts
+pub(crate) async fn write_binary_string_start(stream: &mut (impl AsyncWriteExt + Unpin), size: usize) -> Option<()> {
+    write_raw(stream, format!("${size}{DELIMITER_STR}")).await
 }
 
-pub(crate) async fn write_int(stream: &mut (impl AsyncWriteExt + Unpin), value: i64) -> Option<()> {
-    exec_with_timeout(async move {
-        let result = stream.write_all(format!(":{value}{DELIMITER_STR}").as_bytes()).await;
-        if let Err(error) = result {
-            eprintln!("failed to write integer: {error}");
-            return None;
-        }
-        Some(())
-    }).await
+/// Starts a binary string of unknown size, it ends when the mark is written again.
+pub(crate) async fn write_eof_marked_string_start(stream: &mut (impl AsyncWriteExt + Unpin), mark: &str) -> Option<()> {
+    write_raw(stream, format!("$EOF:{mark}{DELIMITER_STR}")).await
 }
 
 pub(crate) async fn write_array_of_strings<S: AsRef<[u8]>>(stream: &mut (impl AsyncWriteExt + Unpin), strings: impl AsRef<[S]>) -> Option<()> {
@@ -218,12 +452,6 @@ pub(crate) async fn write_array_of_strings<S: AsRef<[u8]>>(stream: &mut (impl As
     }).await
 }
 
-pub(crate) async fn write_array_size(stream: &mut (impl AsyncWriteExt + Unpin), len: usize) -> Option<()> {
-    exec_with_timeout(async move {
-        do_write_array_size(stream, len).await
-    }).await
-}
-
 async fn do_write_array_size(stream: &mut (impl AsyncWriteExt + Unpin), len: usize) -> Option<()> {
     let result = stream.write_all(format!("*{}{DELIMITER_STR}", len).as_bytes()).await;
     if let Err(error) = result {
@@ -233,8 +461,151 @@ async fn do_write_array_size(stream: &mut (impl AsyncWriteExt + Unpin), len: usi
     Some(())
 }
 
-pub(crate) async fn write_command(stream: &mut (impl AsyncWriteExt + Unpin), command: Command) -> Option<()> {
-    write_array_of_strings(stream, command.raw).await
+/// The version of the protocol that the client has chosen with HELLO
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum Protocol {
+    #[default]
+    Resp2,
+    Resp3,
+}
+impl Protocol {
+    pub fn from_version(version: i64) -> Option<Self> {
+        match version {
+            2 => Some(Protocol::Resp2),
+            3 => Some(Protocol::Resp3),
+            _ => None,
+        }
+    }
+    pub fn get_version(&self) -> i64 {
+        match self {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        }
+    }
+}
+
+/// A reply that is prepared while holding the locks, and written after they are released.
+/// RESP3 types are replaced with the closest RESP2 ones for the clients that use RESP2.
+#[derive(Debug)]
+pub(crate) enum Reply {
+    SimpleString(String),
+    Error(String),
+    BinaryString(Vec<u8>),
+    Null,
+    /// RESP2 has a separate null for arrays, in RESP3 it's the same as any other null
+    NullArray,
+    Int(i64),
+    Array(Vec<Reply>),
+    #[allow(dead_code)]
+    Boolean(bool),
+    #[allow(dead_code)]
+    Double(f64),
+    #[allow(dead_code)]
+    BigNumber(String),
+    /// a string with a three letter format, like "txt" or "mkd"
+    Verbatim{format: &'static str, text: Vec<u8>},
+    Map(Vec<(Reply, Reply)>),
+    Set(Vec<Reply>),
+    /// out of band data, like pub/sub messages
+    Push(Vec<Reply>),
+    /// additional information about the reply, RESP2 clients only get the reply itself
+    #[allow(dead_code)]
+    Attribute{attributes: Vec<(Reply, Reply)>, reply: Box<Reply>},
+}
+
+pub(crate) fn encode_reply(reply: &Reply, protocol: Protocol, result: &mut Vec<u8>) {
+    let is_resp3 = protocol == Protocol::Resp3;
+    match reply {
+        Reply::SimpleString(x) => result.extend_from_slice(format!("+{x}{DELIMITER_STR}").as_bytes()),
+        Reply::Error(x) => result.extend_from_slice(format!("-{x}{DELIMITER_STR}").as_bytes()),
+        Reply::BinaryString(x) => encode_sized(b'$', x, result),
+        Reply::Null | Reply::NullArray if is_resp3 => result.extend_from_slice(format!("_{DELIMITER_STR}").as_bytes()),
+        Reply::Null => result.extend_from_slice(format!("$-1{DELIMITER_STR}").as_bytes()),
+        Reply::NullArray => result.extend_from_slice(format!("*-1{DELIMITER_STR}").as_bytes()),
+        Reply::Int(x) => result.extend_from_slice(format!(":{x}{DELIMITER_STR}").as_bytes()),
+        Reply::Array(items) => encode_aggregate(b'*', items, protocol, result),
+        Reply::Boolean(x) if is_resp3 => {
+            let value = if *x { 't' } else { 'f' };
+            result.extend_from_slice(format!("#{value}{DELIMITER_STR}").as_bytes());
+        },
+        Reply::Boolean(x) => result.extend_from_slice(format!(":{}{DELIMITER_STR}", *x as i64).as_bytes()),
+        Reply::Double(x) => {
+            let value = if x.is_nan() { "nan".to_string() } else { x.to_string() };
+            if is_resp3 {
+                result.extend_from_slice(format!(",{value}{DELIMITER_STR}").as_bytes());
+            } else {
+                encode_sized(b'$', value.as_bytes(), result);
+            }
+        },
+        Reply::BigNumber(x) if is_resp3 => result.extend_from_slice(format!("({x}{DELIMITER_STR}").as_bytes()),
+        Reply::BigNumber(x) => encode_sized(b'$', x.as_bytes(), result),
+        Reply::Verbatim { format, text } if is_resp3 => {
+            let data = [format.as_bytes(), b":", text].concat();
+            encode_sized(b'=', &data, result);
+        },
+        Reply::Verbatim { text, .. } => encode_sized(b'$', text, result),
+        Reply::Map(pairs) => {
+            let prefix = if is_resp3 { format!("%{}", pairs.len()) } else { format!("*{}", pairs.len() * 2) };
+            result.extend_from_slice(format!("{prefix}{DELIMITER_STR}").as_bytes());
+            for (key, value) in pairs {
+                encode_reply(key, protocol, result);
+                encode_reply(value, protocol, result);
+            }
+        },
+        Reply::Set(items) => encode_aggregate(if is_resp3 { b'~' } else { b'*' }, items, protocol, result),
+        Reply::Push(items) => encode_aggregate(if is_resp3 { b'>' } else { b'*' }, items, protocol, result),
+        Reply::Attribute { attributes, reply } => {
+            if is_resp3 {
+                result.extend_from_slice(format!("|{}{DELIMITER_STR}", attributes.len()).as_bytes());
+                for (key, value) in attributes {
+                    encode_reply(key, protocol, result);
+                    encode_reply(value, protocol, result);
+                }
+            }
+            encode_reply(reply, protocol, result);
+        },
+    }
+}
+
+fn encode_sized(prefix: u8, data: &[u8], result: &mut Vec<u8>) {
+    result.push(prefix);
+    result.extend_from_slice(format!("{}{DELIMITER_STR}", data.len()).as_bytes());
+    result.extend_from_slice(data);
+    result.extend_from_slice(DELIMITER_BYTES);
+}
+
+fn encode_aggregate(prefix: u8, items: &[Reply], protocol: Protocol, result: &mut Vec<u8>) {
+    result.push(prefix);
+    result.extend_from_slice(format!("{}{DELIMITER_STR}", items.len()).as_bytes());
+    for item in items {
+        encode_reply(item, protocol, result);
+    }
+}
+
+pub(crate) fn encode_command(command: &Command) -> Vec<u8> {
+    let mut result = format!("*{}{DELIMITER_STR}", command.raw.len()).into_bytes();
+    for arg in &command.raw {
+        result.extend_from_slice(format!("${}{DELIMITER_STR}", arg.len()).as_bytes());
+        result.extend_from_slice(arg);
+        result.extend_from_slice(DELIMITER_BYTES);
+    }
+    result
+}
+
+pub(crate) async fn write_raw(stream: &mut (impl AsyncWriteExt + Unpin), data: impl AsRef<[u8]>) -> Option<()> {
+    exec_with_timeout(async move {
+        let result = stream.write_all(data.as_ref()).await;
+        if let Err(error) = result {
+            eprintln!("failed to write raw data: {error}");
+            return None;
+        }
+        Some(())
+    }).await
+}
+
+async fn read_with_timeout<R>(future: impl Future<Output = Result<R, ReadError>>) -> Result<R, ReadError> {
+    exec_with_timeout(async { Some(future.await) }).await
+        .unwrap_or(Err(ReadError::Closed))
 }
 
 async fn exec_with_timeout<R>(future: impl Future<Output = Option<R>>) -> Option<R> {
@@ -243,7 +614,86 @@ async fn exec_with_timeout<R>(future: impl Future<Output = Option<R>>) -> Option
         Ok(x) => x,
         Err(_) => {
             eprintln!("operation timed out");
-            return None;
+            None
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Option<Vec<Vec<u8>>> {
+        split_args(line.as_bytes())
+    }
+
+    fn expected(items: &[&str]) -> Option<Vec<Vec<u8>>> {
+        Some(items.iter().map(|x| x.as_bytes().to_vec()).collect())
+    }
+
+    #[test]
+    fn split_args_plain() {
+        assert_eq!(args("SET key value"), expected(&["SET", "key", "value"]));
+        assert_eq!(args("  PING \t "), expected(&["PING"]));
+        assert_eq!(args(""), expected(&[]));
+        assert_eq!(args("   "), expected(&[]));
+    }
+
+    #[test]
+    fn split_args_quotes() {
+        assert_eq!(args(r#"SET "a key" 'a value'"#), expected(&["SET", "a key", "a value"]));
+        assert_eq!(args(r#"SET k """#), expected(&["SET", "k", ""]));
+        assert_eq!(args(r#"ECHO "\x41\x7a\n\t\"""#), expected(&["ECHO", "Az\n\t\""]));
+        // invalid hex escapes are kept as is, without the backslash
+        assert_eq!(args(r#"ECHO "\xZZ""#), expected(&["ECHO", "xZZ"]));
+        assert_eq!(args(r"ECHO 'it\'s \n'"), expected(&["ECHO", r"it's \n"]));
+        assert_eq!(split_args(br#"ECHO "\xff""#), Some(vec![b"ECHO".to_vec(), vec![0xFF]]));
+    }
+
+    #[test]
+    fn split_args_unbalanced_quotes() {
+        assert_eq!(args(r#"SET "key"#), None);
+        assert_eq!(args("SET 'key"), None);
+        assert_eq!(args(r#"SET "key"value"#), None);
+        assert_eq!(args("SET 'key'value"), None);
+        assert_eq!(args(r#"ECHO "\""#), None);
+    }
+
+    fn encode(reply: &Reply, protocol: Protocol) -> String {
+        let mut result = Vec::new();
+        encode_reply(reply, protocol, &mut result);
+        String::from_utf8(result).unwrap()
+    }
+
+    fn string(value: &str) -> Reply {
+        Reply::BinaryString(value.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn encode_reply_downgrades_resp3_types_for_resp2() {
+        let map = Reply::Map(vec![(string("a"), Reply::Int(1)), (string("b"), Reply::Null)]);
+        assert_eq!(encode(&map, Protocol::Resp2), "*4\r\n$1\r\na\r\n:1\r\n$1\r\nb\r\n$-1\r\n");
+        assert_eq!(encode(&map, Protocol::Resp3), "%2\r\n$1\r\na\r\n:1\r\n$1\r\nb\r\n_\r\n");
+
+        let set = Reply::Set(vec![string("x")]);
+        assert_eq!(encode(&set, Protocol::Resp2), "*1\r\n$1\r\nx\r\n");
+        assert_eq!(encode(&set, Protocol::Resp3), "~1\r\n$1\r\nx\r\n");
+
+        let push = Reply::Push(vec![string("message"), string("ch")]);
+        assert_eq!(encode(&push, Protocol::Resp2), "*2\r\n$7\r\nmessage\r\n$2\r\nch\r\n");
+        assert_eq!(encode(&push, Protocol::Resp3), ">2\r\n$7\r\nmessage\r\n$2\r\nch\r\n");
+
+        assert_eq!(encode(&Reply::Null, Protocol::Resp2), "$-1\r\n");
+        assert_eq!(encode(&Reply::NullArray, Protocol::Resp2), "*-1\r\n");
+        assert_eq!(encode(&Reply::Null, Protocol::Resp3), "_\r\n");
+        assert_eq!(encode(&Reply::NullArray, Protocol::Resp3), "_\r\n");
+
+        let verbatim = Reply::Verbatim { format: "txt", text: b"hello".to_vec() };
+        assert_eq!(encode(&verbatim, Protocol::Resp2), "$5\r\nhello\r\n");
+        assert_eq!(encode(&verbatim, Protocol::Resp3), "=9\r\ntxt:hello\r\n");
+
+        assert_eq!(encode(&Reply::Boolean(true), Protocol::Resp2), ":1\r\n");
+        assert_eq!(encode(&Reply::Boolean(false), Protocol::Resp2), ":0\r\n");
+        assert_eq!(encode(&Reply::Boolean(true), Protocol::Resp3), "#t\r\n");
+    }
+}
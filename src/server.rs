@@ -1,48 +1,237 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::str::FromStr;
 use std::sync::{Arc, RwLock};
-use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use tokio::io::BufReader;
 use tokio::net::{lookup_host, TcpListener, TcpStream};
-use tokio::sync::broadcast::{channel, Receiver, Sender};
+use tokio::sync::watch;
+use tokio::task::AbortHandle;
+use tokio::time::sleep;
+use crate::backlog::Backlog;
 use crate::command::Command;
+use crate::command_table::CommandNames;
 use crate::connection::{handle_external, handle_master, handle_slave};
-use crate::handshake::master_handshake;
-use crate::storage::{Storage, StorageInner};
+use crate::handshake::{master_handshake, receive_rdb, SyncKind};
+use crate::output_buffer::{replica_channel, OutputBufferLimit, OutputBufferLimits, ReplicaReceiver, ReplicaSender};
+use crate::pubsub::{KeyspaceEventClass, KeyspaceEvents, PubSub};
+use crate::resp::{encode_command, ProtocolLimits, DEFAULT_PROTO_MAX_BULK_LEN, DEFAULT_PROTO_MAX_MULTIBULK_LEN};
+use crate::storage::{delete_expired, Storage, StorageInner, StorageKey};
+
+pub(crate) const DEFAULT_BACKLOG_SIZE: usize = 1024 * 1024;
+pub(crate) const DEFAULT_MIN_REPLICAS_MAX_LAG: u64 = 10;
+const EMPTY_REPLICATION_ID: &str = "0000000000000000000000000000000000000000";
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+const ACTIVE_EXPIRE_INTERVAL: Duration = Duration::from_secs(1);
+const ACTIVE_EXPIRE_KEYS_PER_LOOP: usize = 20;
+/// the cycle goes on while more than this percent of the picked keys turn out to be expired
+const ACTIVE_EXPIRE_ACCEPTABLE_STALE_PERCENT: usize = 10;
+const ACTIVE_EXPIRE_TIME_LIMIT: Duration = Duration::from_millis(25);
 
 pub(crate) struct Server {
-    pub is_slave: bool,
-    pub replication_id: String,
-    pub slave_read_offset: AtomicUsize,
+    pub port: u16,
+    pub role: RwLock<Role>,
     pub storage: Storage,
     pub replication: RwLock<Replication>,
     pub slave_state: RwLock<SlaveState>,
     pub config: Config,
+    pub pubsub: RwLock<PubSub>,
+    min_replicas_to_write: usize,
+    min_replicas_max_lag: Duration,
+    pub repl_diskless_sync: bool,
+    notify_keyspace_events: KeyspaceEvents,
+    next_client_id: AtomicUsize,
+    pub protocol_limits: ProtocolLimits,
+    pub command_names: CommandNames,
 }
 impl Server {
-    fn new(storage: StorageInner, config: Config, master_config: Option<(String, usize)>) -> Self {
-        let (repl_tx, _) = channel(100);
-        let (is_slave, replication_id, offset) = match master_config {
-            Some(x) => (true, x.0, x.1),
-            None => (false, "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb".to_string(), 0),
+    fn new(storage: StorageInner, port: u16, config: Config, command_names: CommandNames) -> Self {
+        let backlog_size = get_config_value(&config, "repl-backlog-size").unwrap_or(DEFAULT_BACKLOG_SIZE);
+        let output_buffer_limits: OutputBufferLimits = get_config_value(&config, "client-output-buffer-limit").unwrap_or_default();
+        let min_replicas_to_write = get_config_value(&config, "min-replicas-to-write").unwrap_or(0);
+        let min_replicas_max_lag = get_config_value(&config, "min-replicas-max-lag")
+            .unwrap_or(DEFAULT_MIN_REPLICAS_MAX_LAG);
+        let repl_diskless_sync = get_config_value::<String>(&config, "repl-diskless-sync")
+            .is_some_and(|x| x == "yes");
+        let notify_keyspace_events = get_config_value(&config, "notify-keyspace-events").unwrap_or_default();
+        let protocol_limits = ProtocolLimits {
+            max_bulk_len: get_config_value(&config, "proto-max-bulk-len").unwrap_or(DEFAULT_PROTO_MAX_BULK_LEN),
+            max_multibulk_len: get_config_value(&config, "proto-max-multibulk-len").unwrap_or(DEFAULT_PROTO_MAX_MULTIBULK_LEN),
         };
         Self {
-            is_slave,
-            replication_id,
-            slave_read_offset: offset.into(),
+            port,
+            role: RwLock::new(Role::Master),
             storage: Storage::new(storage),
-            replication: RwLock::new(Replication {
-                sender: repl_tx,
-                master_written_offset: 0,
-            }),
+            replication: RwLock::new(Replication::new(backlog_size, output_buffer_limits.replica)),
             slave_state: Default::default(),
             config,
+            pubsub: RwLock::new(PubSub::new(output_buffer_limits.pubsub)),
+            min_replicas_to_write,
+            min_replicas_max_lag: Duration::from_secs(min_replicas_max_lag),
+            repl_diskless_sync,
+            notify_keyspace_events,
+            next_client_id: AtomicUsize::new(1),
+            protocol_limits,
+            command_names,
+        }
+    }
+    pub(crate) fn new_arc(storage: StorageInner, port: u16, config: Config, command_names: CommandNames) -> Arc<Self> {
+        Arc::new(Self::new(storage, port, config, command_names))
+    }
+    pub fn new_client_id(&self) -> usize {
+        self.next_client_id.fetch_add(1, Ordering::Relaxed)
+    }
+    pub fn is_slave(&self) -> bool {
+        matches!(*self.role.read().expect("got poisoned lock"), Role::Slave{..})
+    }
+    /// Whether enough replicas have acknowledged recently, for the writes to be accepted.
+    pub fn has_enough_good_replicas(&self) -> bool {
+        if self.min_replicas_to_write == 0 {
+            return true;
+        }
+        let good_count = self.slave_state.read().expect("got poisoned lock")
+            .count_good(self.min_replicas_max_lag);
+        good_count >= self.min_replicas_to_write
+    }
+    /// Waits until at least `need_count` replicas acknowledge the offset, returns the number of acknowledged replicas.
+    pub async fn wait_acknowledged_replicas(&self, offset: usize, need_count: usize, mut acks: watch::Receiver<()>) -> usize {
+        loop {
+            let (acked_count, waiting_count) = self.slave_state.read().expect("got poisoned lock")
+                .check_acknowledged(offset);
+            if (acked_count >= need_count) || (waiting_count == 0) {
+                return acked_count;
+            }
+            if acks.changed().await.is_err() {
+                return acked_count;
+            }
+        }
+    }
+    /*
+    Replicas never delete expired keys on their own, they only hide them,
+    and wait for master to send DEL, so that the data on master and replicas is always the same.
+    `storage` is the locked data, the returned command has to be replicated under the same lock.
+     */
+    pub fn expire_if_needed_in(&self, storage: &mut StorageInner, key: &StorageKey) -> Option<Command> {
+        if self.is_slave() || !delete_expired(storage, key) {
+            return None;
+        }
+        self.storage.touch_expired(key);
+        self.notify_keyspace_event(KeyspaceEventClass::Expired, "expired", key);
+        let command = Command::new(vec![b"DEL".to_vec(), key.clone()])
+            .expect("hardcoded command should be valid");
+        Some(command)
+    }
+    /// Publishes the event to __keyspace@0__:<key> and __keyevent@0__:<event>, if it's enabled
+    pub fn notify_keyspace_event(&self, class: KeyspaceEventClass, event: &str, key: &[u8]) {
+        let flags = self.notify_keyspace_events;
+        if !flags.is_enabled(class) {
+            return;
+        }
+        let pubsub = self.pubsub.read().expect("got poisoned lock");
+        if flags.is_keyspace_enabled() {
+            let channel = [b"__keyspace@0__:".as_slice(), key].concat();
+            pubsub.publish(&channel, event.as_bytes());
+        }
+        if flags.is_keyevent_enabled() {
+            let channel = format!("__keyevent@0__:{event}");
+            pubsub.publish(channel.as_bytes(), key);
         }
     }
-    fn new_arc(storage: StorageInner, config: Config, master_config: Option<(String, usize)>) -> Arc<Self> {
-        Arc::new(Self::new(storage, config, master_config))
+    /*
+    Expired keys that nobody reads would stay in memory forever, so they are also removed periodically.
+    Same as in redis, only a few random keys with a TTL are checked at a time, so that the storage is never locked for long,
+    and more of them are checked only while a lot of the checked ones turn out to be expired.
+     */
+    fn active_expire_cycle(&self) {
+        let started_at = Instant::now();
+        loop {
+            let (checked, removed) = self.active_expire_step();
+            let is_mostly_clean = removed * 100 <= checked * ACTIVE_EXPIRE_ACCEPTABLE_STALE_PERCENT;
+            if (checked == 0) || is_mostly_clean || (started_at.elapsed() > ACTIVE_EXPIRE_TIME_LIMIT) {
+                return;
+            }
+        }
+    }
+    /// Returns the number of keys that were checked, and how many of them were expired or don't have a TTL anymore
+    fn active_expire_step(&self) -> (usize, usize) {
+        let mut guard = self.storage.write_all();
+        let (keys, forgotten) = self.storage.sample_volatile_keys(&guard, ACTIVE_EXPIRE_KEYS_PER_LOOP);
+        let mut expired = 0;
+        for key in &keys {
+            let Some(command) = self.expire_if_needed_in(&mut guard, key) else {
+                continue;
+            };
+            self.replication.write().expect("got poisoned lock")
+                .send(command);
+            expired += 1;
+        }
+        drop(guard);
+        (keys.len() + forgotten, expired + forgotten)
+    }
+    pub fn get_master_link(&self) -> Option<Arc<RwLock<MasterLink>>> {
+        match &*self.role.read().expect("got poisoned lock") {
+            Role::Slave { link, .. } => Some(Arc::clone(link)),
+            Role::Master => None,
+        }
+    }
+    /// Returns false if we are already replicating from this master
+    pub fn become_slave(self: &Arc<Self>, host: String, port: u16, is_synced: bool) -> bool {
+        let mut role = self.role.write().expect("got poisoned lock");
+        let is_synced = match &*role {
+            Role::Slave { link, supervisor } => {
+                let link = link.read().expect("got poisoned lock");
+                if (link.host == host) && (link.port == port) {
+                    return false;
+                }
+                supervisor.abort();
+                link.is_synced
+            },
+            // our own data can be used to continue replication from a master that used to be our replica
+            Role::Master => is_synced,
+        };
+        // replicas of this server need to sync with the new history
+        self.replication.write().expect("got poisoned lock").disconnect_replicas();
+        let link = Arc::new(RwLock::new(MasterLink::new(host, port, is_synced)));
+        let supervisor = {
+            let server = Arc::clone(self);
+            let link = Arc::clone(&link);
+            tokio::spawn(async move {
+                supervise_master_link(server, link).await
+            })
+        };
+        *role = Role::Slave { link, supervisor: supervisor.abort_handle() };
+        true
+    }
+    pub fn become_master(&self) {
+        let mut role = self.role.write().expect("got poisoned lock");
+        let Role::Slave { supervisor, .. } = &*role else {
+            return;
+        };
+        supervisor.abort();
+        self.replication.write().expect("got poisoned lock").start_new_history(generate_replication_id());
+        *role = Role::Master;
     }
 }
 
+pub(crate) enum Role {
+    Master,
+    Slave{link: Arc<RwLock<MasterLink>>, supervisor: AbortHandle},
+}
+
+pub(crate) fn generate_replication_id() -> String {
+    // each RandomState is seeded with random keys, which is good enough for an id
+    let mut result = String::new();
+    while result.len() < 40 {
+        let value = RandomState::new().build_hasher().finish();
+        result.push_str(format!("{value:016x}").as_str());
+    }
+    result.truncate(40);
+    result
+}
+
 /*
 We need to know which offset corresponds to which command.
 This is needed for 2 things:
@@ -51,90 +240,329 @@ and when replicas acknowledge some offset, we need to know if this includes a sp
 The simplest way to implement this is to ensure that all commands are replicated to all slaves in exactly the same order, and each command has a known offset.
 Which requires a global lock.
 Alternatively we could try keeping a separate replication log for each replica, but getting an offset for a specific command would be way too complicated.
+The same bytes that are sent to replicas are also written to the backlog,
+so that a replica that has lost the connection can continue from its offset instead of doing a full resync.
  */
 pub(crate) struct Replication {
-    sender: Sender<Command>,
+    replication_id: String,
+    /// the id of the history that we had before the current one, after a slave was promoted to master
+    replication_id2: String,
+    /// replicas of the previous history can continue up to this offset
+    second_offset: Option<usize>,
+    replicas: Vec<ReplicaSender>,
+    output_buffer_limit: OutputBufferLimit,
     master_written_offset: usize,
+    backlog: Backlog,
 }
 impl Replication {
+    fn new(backlog_size: usize, output_buffer_limit: OutputBufferLimit) -> Self {
+        Self {
+            // slaves will replace it with master's id when they sync with it
+            replication_id: generate_replication_id(),
+            replication_id2: EMPTY_REPLICATION_ID.to_string(),
+            second_offset: None,
+            replicas: Vec::new(),
+            output_buffer_limit,
+            master_written_offset: 0,
+            backlog: Backlog::new(backlog_size, 0),
+        }
+    }
     pub fn send(&mut self, command: Command) -> usize {
-        self.master_written_offset += command.byte_size;
-        let _ = self.sender.send(command);
+        self.send_raw(encode_command(&command))
+    }
+    /// Replicas forward the stream from their master as is, so that the offsets stay the same on all of them.
+    pub fn send_raw(&mut self, data: Vec<u8>) -> usize {
+        self.backlog.append(&data);
+        self.master_written_offset += data.len();
+        let data: Arc<[u8]> = data.into();
+        self.replicas.retain(|x| x.send(Arc::clone(&data)));
         self.master_written_offset
     }
-    pub fn subscribe(&self) -> Receiver<Command> {
-        self.sender.subscribe()
+    pub fn subscribe(&mut self) -> ReplicaReceiver {
+        let (sender, receiver) = replica_channel(self.output_buffer_limit);
+        self.replicas.push(sender);
+        receiver
+    }
+    /// Returns the current id, the part of the stream starting from the offset, and a receiver for everything after it.
+    /// None means that the replica has a different history, or that the offset is no longer (or not yet) in the backlog.
+    pub fn subscribe_from(&mut self, replication_id: &[u8], offset: usize) -> Option<(String, Vec<u8>, ReplicaReceiver)> {
+        let is_current_history = replication_id == self.replication_id.as_bytes();
+        let is_previous_history = (replication_id == self.replication_id2.as_bytes())
+            && self.second_offset.is_some_and(|x| offset <= x);
+        if !is_current_history && !is_previous_history {
+            return None;
+        }
+        let Some(data) = self.backlog.read_from(offset) else {
+            eprintln!("offset {offset} requested by replica is not in the backlog");
+            return None;
+        };
+        Some((self.replication_id.clone(), data, self.subscribe()))
+    }
+    pub fn get_id(&self) -> &str {
+        &self.replication_id
+    }
+    pub fn get_id2(&self) -> &str {
+        &self.replication_id2
+    }
+    pub fn get_second_offset(&self) -> Option<usize> {
+        self.second_offset
+    }
+    pub fn get_offset(&self) -> usize {
+        self.master_written_offset
+    }
+    pub fn get_backlog(&self) -> &Backlog {
+        &self.backlog
+    }
+    /// After a full resync we have master's history, and nothing from our own history is valid anymore
+    fn reset(&mut self, replication_id: String, offset: usize) {
+        self.replication_id = replication_id;
+        self.replication_id2 = EMPTY_REPLICATION_ID.to_string();
+        self.second_offset = None;
+        self.master_written_offset = offset;
+        self.backlog = Backlog::new(self.backlog.get_size(), offset);
+        self.disconnect_replicas();
+    }
+    fn start_new_history(&mut self, replication_id: String) {
+        let previous_id = std::mem::replace(&mut self.replication_id, replication_id);
+        self.replication_id2 = previous_id;
+        self.second_offset = Some(self.master_written_offset);
+        self.disconnect_replicas();
+    }
+    fn disconnect_replicas(&mut self) {
+        // receivers get an error once their senders are gone, and disconnect
+        self.replicas.clear();
+    }
+}
+
+pub(crate) struct MasterLink {
+    pub host: String,
+    pub port: u16,
+    pub state: MasterLinkState,
+    /// None if the link is up, or if it was never up
+    pub down_since: Option<Instant>,
+    /// false until we get the data from master for the first time
+    pub is_synced: bool,
+}
+impl MasterLink {
+    fn new(host: String, port: u16, is_synced: bool) -> Self {
+        Self {
+            host,
+            port,
+            state: MasterLinkState::Connecting,
+            down_since: None,
+            is_synced,
+        }
+    }
+    fn set_state(&mut self, state: MasterLinkState) {
+        if (self.state == MasterLinkState::Connected) && (state != MasterLinkState::Connected) {
+            self.down_since = Some(Instant::now());
+        }
+        if state == MasterLinkState::Connected {
+            self.down_since = None;
+        }
+        self.state = state;
     }
 }
 
-#[derive(Default)]
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub(crate) enum MasterLinkState {
+    Connecting,
+    Handshake,
+    Sync,
+    Connected,
+}
+impl MasterLinkState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MasterLinkState::Connecting => "connecting",
+            MasterLinkState::Handshake => "handshake",
+            MasterLinkState::Sync => "sync",
+            MasterLinkState::Connected => "connected",
+        }
+    }
+}
+
+pub(crate) struct SlaveInfo {
+    pub ip: String,
+    pub port: u16,
+    pub offset: usize,
+    pub last_ack_at: Instant,
+}
+
 pub(crate) struct SlaveState {
-    offsets: HashMap<usize, usize>,
+    slaves: BTreeMap<usize, SlaveInfo>,
     next_id: usize,
+    // WAIT listens here, instead of polling the offsets
+    ack_sender: watch::Sender<()>,
+}
+impl Default for SlaveState {
+    fn default() -> Self {
+        Self {
+            slaves: Default::default(),
+            next_id: 0,
+            ack_sender: watch::channel(()).0,
+        }
+    }
 }
 impl SlaveState {
-    pub fn connect(&mut self) -> usize {
+    pub fn connect(&mut self, ip: String, port: u16) -> usize {
         let id = self.next_id;
         self.next_id += 1;
-        self.offsets.insert(id, 0);
+        self.slaves.insert(id, SlaveInfo { ip, port, offset: 0, last_ack_at: Instant::now() });
         id
     }
     pub fn update_offset(&mut self, id: usize, offset: usize) -> bool {
-        let old_value = self.offsets.get(&id)
+        let slave = self.slaves.get_mut(&id)
             .expect("update should only happen for valid ids");
-        if *old_value > offset {
+        if slave.offset > offset {
             return false;
         }
-        self.offsets.insert(id, offset);
-        return true;
+        slave.offset = offset;
+        slave.last_ack_at = Instant::now();
+        self.ack_sender.send_replace(());
+        true
     }
     pub fn disconnect(&mut self, id: usize) {
-        self.offsets.remove(&id);
+        self.slaves.remove(&id);
     }
     pub fn check_acknowledged(&self, offset: usize) -> (usize, usize) {
-        let count_acknowledged = self.offsets
-            .iter()
-            .filter(|(_, &v)| {
-                v >= offset
-            })
+        let count_acknowledged = self.slaves
+            .values()
+            .filter(|x| x.offset >= offset)
             .count();
-        (count_acknowledged, self.offsets.len() - count_acknowledged)
+        (count_acknowledged, self.slaves.len() - count_acknowledged)
+    }
+    pub fn count_good(&self, max_lag: Duration) -> usize {
+        self.slaves
+            .values()
+            .filter(|x| x.last_ack_at.elapsed() <= max_lag)
+            .count()
+    }
+    pub fn subscribe_acks(&self) -> watch::Receiver<()> {
+        self.ack_sender.subscribe()
+    }
+    pub fn get_slaves(&self) -> impl Iterator<Item = &SlaveInfo> {
+        self.slaves.values()
     }
 }
 
 pub(crate) type Config = HashMap<&'static str, Vec<u8>>;
 
-pub(crate) async fn run_master(storage: StorageInner, port: u16, config: Config) {
-    serve_external_connections(port, Server::new_arc(storage, config, None)).await
+fn get_config_value<T: FromStr>(config: &Config, key: &str) -> Option<T> {
+    let value = config.get(key)?;
+    let Ok(value) = std::str::from_utf8(value) else {
+        eprintln!("config value for {key} is not a valid string");
+        return None;
+    };
+    match value.parse() {
+        Ok(x) => Some(x),
+        Err(_) => {
+            eprintln!("config value for {key} is not valid: {value}");
+            None
+        },
+    }
 }
 
-pub(crate) async fn run_slave(storage: StorageInner, port: u16, config: Config, master_addr: &str) {
-    let master_socket = lookup_host(&master_addr).await
-        .expect(format!("Failed to lookup the address of master host {master_addr}").as_str())
-        .next()
-        .expect(format!("No addresses found for master host {master_addr}").as_str());
+pub(crate) async fn run_master(storage: StorageInner, port: u16, config: Config, command_names: CommandNames) {
+    serve_external_connections(Server::new_arc(storage, port, config, command_names)).await
+}
 
-    let master_stream = TcpStream::connect(master_socket).await
-        .expect("failed to connect to master");
+pub(crate) async fn run_slave(port: u16, config: Config, command_names: CommandNames, master_host: String, master_port: u16) {
+    let server = Server::new_arc(Default::default(), port, config, command_names);
+    server.become_slave(master_host, master_port, false);
+    serve_external_connections(server).await
+}
+
+/*
+Connection to master goes through these states: connecting -> handshake -> sync -> connected.
+If anything fails, or if the connection is lost, we start over after a delay that grows with each failed attempt.
+When reconnecting, we ask master to continue from our offset, so that we don't need to load the whole data again.
+This task is aborted when the server is switched to a different master, or is promoted to master.
+ */
+async fn supervise_master_link(server: Arc<Server>, link: Arc<RwLock<MasterLink>>) {
+    let mut delay = MIN_RECONNECT_DELAY;
+    loop {
+        match connect_to_master(&server, &link).await {
+            Some(master_stream) => {
+                link.write().expect("got poisoned lock").set_state(MasterLinkState::Connected);
+                delay = MIN_RECONNECT_DELAY;
+                handle_master(master_stream, Arc::clone(&server)).await;
+                eprintln!("lost connection to master!");
+            },
+            None => eprintln!("failed to connect to master, retrying in {delay:?}"),
+        }
+        link.write().expect("got poisoned lock").set_state(MasterLinkState::Connecting);
+        sleep(delay).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+async fn connect_to_master(server: &Server, link: &RwLock<MasterLink>) -> Option<BufReader<TcpStream>> {
+    let (master_addr, is_synced) = {
+        let link = link.read().expect("got poisoned lock");
+        (format!("{}:{}", link.host, link.port), link.is_synced)
+    };
+    let master_socket = match lookup_host(&master_addr).await {
+        Ok(mut x) => x.next(),
+        Err(err) => {
+            eprintln!("Failed to lookup the address of master host {master_addr} {err}");
+            return None;
+        },
+    };
+    let Some(master_socket) = master_socket else {
+        eprintln!("No addresses found for master host {master_addr}");
+        return None;
+    };
+    let master_stream = match TcpStream::connect(master_socket).await {
+        Ok(x) => x,
+        Err(err) => {
+            eprintln!("failed to connect to master {err}");
+            return None;
+        },
+    };
     let mut master_stream = BufReader::new(master_stream);
-    let master_config = master_handshake(&mut master_stream, port).await;
 
-    let server = Server::new_arc(storage, config, Some(master_config));
+    link.write().expect("got poisoned lock").set_state(MasterLinkState::Handshake);
+    let (replication_id, offset) = {
+        let replication = server.replication.read().expect("got poisoned lock");
+        (replication.get_id().to_string(), replication.get_offset())
+    };
+    let replication_id = is_synced.then_some(replication_id);
+    let sync_kind = master_handshake(&mut master_stream, server.port, replication_id.as_deref(), offset).await?;
 
-    {
-        let server = Arc::clone(&server);
-        tokio::spawn(async move {
-            handle_master(master_stream, server).await;
-            eprintln!("lost connection to master!");
-        });
+    link.write().expect("got poisoned lock").set_state(MasterLinkState::Sync);
+    match sync_kind {
+        SyncKind::FullResync { replication_id, offset } => {
+            let storage = receive_rdb(&mut master_stream).await?;
+            server.storage.replace(storage);
+            server.replication.write().expect("got poisoned lock").reset(replication_id, offset);
+            link.write().expect("got poisoned lock").is_synced = true;
+        },
+        SyncKind::Continue { replication_id } => {
+            if let Some(replication_id) = replication_id {
+                let mut replication = server.replication.write().expect("got poisoned lock");
+                if replication_id != replication.get_id() {
+                    // master was promoted, and has started a new history
+                    replication.start_new_history(replication_id);
+                }
+            }
+        },
     }
+    Some(master_stream)
+}
 
-    serve_external_connections(port, server).await
+async fn run_active_expire(server: Arc<Server>) {
+    loop {
+        sleep(ACTIVE_EXPIRE_INTERVAL).await;
+        server.active_expire_cycle();
+    }
 }
 
-async fn serve_external_connections(port: u16, server: Arc<Server>) {
+async fn serve_external_connections(server: Arc<Server>) {
+    tokio::spawn(run_active_expire(Arc::clone(&server)));
+    let port = server.port;
     let listener = TcpListener::bind(format!("127.0.0.1:{port}")).await
-        .expect(format!("Failed to bind to the port {port}").as_str());
+        .unwrap_or_else(|_| panic!("Failed to bind to the port {port}"));
     loop {
         let (stream, _addr) = listener.accept().await
             .expect("Failed to accept connection");
@@ -1,97 +1,297 @@
 use std::collections::HashMap;
-use std::sync::{RwLock, RwLockWriteGuard};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::time::SystemTime;
 
 type BinaryData = Vec<u8>;
 pub(crate) type StorageKey = BinaryData;
-pub(crate) type StorageInner = HashMap<StorageKey, StorageItem>;
+/// The values are shared with the snapshots that are being sent to replicas, and are copied if modified in the meantime
+pub(crate) type StorageInner = HashMap<StorageKey, Arc<StorageItem>>;
 pub(crate) type ExpiryTs = u128;
 
 #[derive(Default)]
 pub(crate) struct Storage {
-    inner: RwLock<StorageInner>
+    inner: RwLock<StorageInner>,
+    /// always locked after `inner`
+    watched_keys: Mutex<HashMap<StorageKey, WatchedKey>>,
+    /// always locked after `inner`
+    volatile_keys: Mutex<VolatileKeys>,
+}
+/*
+The keys that have a TTL, so that the active expire can pick random ones without going through the whole storage.
+It may also have the keys that don't have a TTL anymore, those are forgotten when they get picked.
+ */
+#[derive(Default)]
+struct VolatileKeys {
+    keys: Vec<StorageKey>,
+    positions: HashMap<StorageKey, usize>,
+}
+impl VolatileKeys {
+    fn from_storage(storage: &StorageInner) -> Self {
+        let mut result = Self::default();
+        for key in storage.keys().filter(|key| get_expiry(storage, key).is_some()) {
+            result.insert(key);
+        }
+        result
+    }
+    fn insert(&mut self, key: &StorageKey) {
+        if self.positions.contains_key(key) {
+            return;
+        }
+        self.positions.insert(key.clone(), self.keys.len());
+        self.keys.push(key.clone());
+    }
+    fn remove(&mut self, key: &StorageKey) {
+        let Some(position) = self.positions.remove(key) else {
+            return;
+        };
+        self.keys.swap_remove(position);
+        if let Some(moved) = self.keys.get(position) {
+            self.positions.insert(moved.clone(), position);
+        }
+    }
+}
+/// Versions are only tracked for the keys that somebody is watching
+#[derive(Default)]
+struct WatchedKey {
+    /// changes every time the key is modified, except when it's deleted because it has expired
+    version: u64,
+    expired_deletions: u64,
+    watchers: usize,
+}
+/// What WATCH remembers about the key, to tell at EXEC if it was modified
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct WatchedVersion {
+    version: u64,
+    expired_deletions: u64,
+    /// same as in redis, deleting a key that was already expired at WATCH is not a modification
+    is_expired: bool,
 }
 impl Storage {
     pub(crate) fn new(inner: StorageInner) -> Self {
-        Self{ inner: RwLock::new(inner) }
+        let volatile_keys = VolatileKeys::from_storage(&inner);
+        Self{ inner: RwLock::new(inner), watched_keys: Default::default(), volatile_keys: Mutex::new(volatile_keys) }
     }
 
-    pub(crate) fn get_simple(&self, key: &StorageKey) -> Option<SimpleValue> {
-        let guard = self.inner.read().expect("got poisoned lock, can't handle that");
-        let Some(StorageItem::Simple(item)) = guard.get(key) else {
-            return None;
-        };
-        if item.is_expired() {
-            drop(guard);
-            self.delete_expired(key);
-            return None;
+    pub(crate) fn replace(&self, inner: StorageInner) {
+        let mut guard = self.inner.write().expect("got poisoned lock, can't handle that");
+        *self.volatile_keys.lock().expect("got poisoned lock, can't handle that") = VolatileKeys::from_storage(&inner);
+        *guard = inner;
+        self.touch_all();
+    }
+
+    pub(crate) fn read_all(&self) -> RwLockReadGuard<'_, StorageInner> {
+        self.inner.read().expect("got poisoned lock, can't handle that")
+    }
+
+    /// Should be called under the write lock, after a TTL is set for the key
+    pub(crate) fn add_volatile(&self, key: &StorageKey) {
+        self.volatile_keys.lock().expect("got poisoned lock, can't handle that")
+            .insert(key);
+    }
+
+    /// Picks up to `count` random keys that have a TTL, `storage` is the locked data.
+    /// The keys that don't have a TTL anymore are forgotten on the way, their number is returned too.
+    pub(crate) fn sample_volatile_keys(&self, storage: &StorageInner, count: usize) -> (Vec<StorageKey>, usize) {
+        let mut volatile_keys = self.volatile_keys.lock().expect("got poisoned lock, can't handle that");
+        let size = volatile_keys.keys.len();
+        if size == 0 {
+            return (Vec::new(), 0);
+        }
+        // the order of the keys is arbitrary, so the ones after a random position are as good as random ones
+        let start = random_index(size);
+        let picked: Vec<_> = (0..count.min(size))
+            .map(|i| volatile_keys.keys[(start + i) % size].clone())
+            .collect();
+        let (result, forgotten): (Vec<_>, Vec<_>) = picked.into_iter()
+            .partition(|key| get_expiry(storage, key).is_some());
+        for key in &forgotten {
+            volatile_keys.remove(key);
         }
-        return Some(item.value.clone());
+        (result, forgotten.len())
     }
 
-    pub(crate) fn get_value_kind(&self, key: &StorageKey) -> &'static str {
+    pub(crate) fn write_all(&self) -> RwLockWriteGuard<'_, StorageInner> {
+        self.inner.write().expect("got poisoned lock, can't handle that")
+    }
+
+    /// Returns the current version of the key, it changes every time the key is modified
+    pub(crate) fn watch(&self, key: &StorageKey) -> WatchedVersion {
+        // writes bump the versions under the write lock, so we can't miss one that is in progress
         let guard = self.inner.read().expect("got poisoned lock, can't handle that");
-        let Some(item) = guard.get(key) else {
-            return "none";
+        let mut watched_keys = self.watched_keys.lock().expect("got poisoned lock, can't handle that");
+        let watched_key = watched_keys.entry(key.clone()).or_default();
+        watched_key.watchers += 1;
+        WatchedVersion {
+            version: watched_key.version,
+            expired_deletions: watched_key.expired_deletions,
+            is_expired: guard.get(key).is_some_and(|x| x.is_expired()),
+        }
+    }
+
+    pub(crate) fn unwatch(&self, key: &StorageKey) {
+        let mut watched_keys = self.watched_keys.lock().expect("got poisoned lock, can't handle that");
+        let Some(watched_key) = watched_keys.get_mut(key) else {
+            return;
         };
-        match item {
-            StorageItem::Simple(x) => if x.is_expired() {
-                "none"
-            } else {
-                "string"
-            },
-            StorageItem::Stream(_) => "stream",
+        watched_key.watchers -= 1;
+        if watched_key.watchers == 0 {
+            watched_keys.remove(key);
         }
     }
 
-    pub(crate) fn keys(&self) -> Vec<StorageKey> {
-        let guard = self.inner.read().expect("got poisoned lock, can't handle that");
-        return guard.keys().cloned().collect();
+    /// Should be called under the write lock, after the key is modified
+    pub(crate) fn touch(&self, key: &StorageKey) {
+        let mut watched_keys = self.watched_keys.lock().expect("got poisoned lock, can't handle that");
+        if let Some(watched_key) = watched_keys.get_mut(key) {
+            watched_key.version += 1;
+        }
     }
 
-    pub(crate) fn set_string(&self, key: Vec<u8>, item: StorageItemSimple) -> RwLockWriteGuard<StorageInner> {
-        let mut guard = self.inner.write().expect("got poisoned lock, can't handle that");
-        guard.insert(key, StorageItem::Simple(item));
-        guard
+    /// Same as touch, but for the keys that are deleted because they have expired
+    pub(crate) fn touch_expired(&self, key: &StorageKey) {
+        self.volatile_keys.lock().expect("got poisoned lock, can't handle that")
+            .remove(key);
+        let mut watched_keys = self.watched_keys.lock().expect("got poisoned lock, can't handle that");
+        if let Some(watched_key) = watched_keys.get_mut(key) {
+            watched_key.expired_deletions += 1;
+        }
     }
 
-    pub(crate) fn increment(&self, key: Vec<u8>) -> Option<(RwLockWriteGuard<StorageInner>, i64)> {
-        let mut guard = self.inner.write().expect("got poisoned lock, can't handle that");
-        let entry = guard.entry(key)
-            .or_insert_with(|| StorageItem::Simple(StorageItemSimple{ value: SimpleValue::Int(0), expires_at: None }));
-        let value = match entry {
-            StorageItem::Simple(x) => match &mut x.value {
-                SimpleValue::Int(x) => x,
-                _ => return None,
-            },
-            _ => return None,
+    fn touch_all(&self) {
+        let mut watched_keys = self.watched_keys.lock().expect("got poisoned lock, can't handle that");
+        for watched_key in watched_keys.values_mut() {
+            watched_key.version += 1;
+        }
+    }
+
+    /// Should be called under the lock, `storage` is the locked data
+    pub(crate) fn is_modified(&self, storage: &StorageInner, key: &StorageKey, watched: &WatchedVersion) -> bool {
+        let watched_keys = self.watched_keys.lock().expect("got poisoned lock, can't handle that");
+        let Some(watched_key) = watched_keys.get(key) else {
+            return true;
         };
-        *value += 1;
-        let copy = *value;
-        Some((guard, copy))
+        if watched_key.version != watched.version {
+            return true;
+        }
+        if watched.is_expired {
+            // it can only be deleted, any other change would have changed the version
+            return false;
+        }
+        // a key that is expired now was modified too, even if nobody has deleted it yet
+        watched_key.expired_deletions != watched.expired_deletions
+            || storage.get(key).is_some_and(|x| x.is_expired())
     }
-    
-    pub(crate) fn append_to_stream(&self, key: Vec<u8>, item: StreamEntry) -> Option<RwLockWriteGuard<StorageInner>> {
-        let mut guard = self.inner.write().expect("got poisoned lock, can't handle that");
-        let entry = guard.entry(key)
-            .or_insert_with(|| StorageItem::Stream(Default::default()));
-        let stream = match entry {
-            StorageItem::Stream(x) => x,
+}
+
+/*
+Operations that change the data work on the already locked storage,
+so that the caller can do several of them, and replicate them, under the same lock.
+ */
+pub(crate) fn get_value_kind(storage: &StorageInner, key: &StorageKey) -> &'static str {
+    let Some(item) = storage.get(key) else {
+        return "none";
+    };
+    match item.as_ref() {
+        StorageItem::Simple(x) => if x.is_expired() {
+            "none"
+        } else {
+            "string"
+        },
+        StorageItem::Stream(_) => "stream",
+    }
+}
+
+pub(crate) fn keys(storage: &StorageInner) -> Vec<StorageKey> {
+    storage.iter()
+        .filter(|(_, item)| !item.is_expired())
+        .map(|(key, _)| key.clone())
+        .collect()
+}
+
+pub(crate) fn get_simple<'a>(storage: &'a StorageInner, key: &StorageKey) -> Option<&'a SimpleValue> {
+    let Some(StorageItem::Simple(item)) = storage.get(key).map(|x| x.as_ref()) else {
+        return None;
+    };
+    // expired keys are deleted separately, because the deletion has to be replicated
+    if item.is_expired() {
+        return None;
+    }
+    Some(&item.value)
+}
+
+pub(crate) fn set_string(storage: &mut StorageInner, key: StorageKey, item: StorageItemSimple) {
+    storage.insert(key, Arc::new(StorageItem::Simple(item)));
+}
+
+pub(crate) fn increment(storage: &mut StorageInner, key: StorageKey) -> Option<i64> {
+    let entry = storage.entry(key)
+        .or_insert_with(|| Arc::new(StorageItem::Simple(StorageItemSimple{ value: SimpleValue::Int(0), expires_at: None })));
+    let value = match Arc::make_mut(entry) {
+        StorageItem::Simple(x) => match &mut x.value {
+            SimpleValue::Int(x) => x,
             _ => return None,
-        };
-        stream.push(item);
-        Some(guard)
+        },
+        _ => return None,
+    };
+    *value += 1;
+    Some(*value)
+}
+
+pub(crate) fn append_to_stream(storage: &mut StorageInner, key: StorageKey, item: StreamEntry) -> Option<()> {
+    let entry = storage.entry(key)
+        .or_insert_with(|| Arc::new(StorageItem::Stream(Default::default())));
+    let stream = match Arc::make_mut(entry) {
+        StorageItem::Stream(x) => x,
+        _ => return None,
+    };
+    stream.push(item);
+    Some(())
+}
+
+/// Returns the keys that existed
+pub(crate) fn delete(storage: &mut StorageInner, keys: &[StorageKey]) -> Vec<StorageKey> {
+    keys.iter()
+        .filter(|key| storage.remove(*key).is_some())
+        .cloned()
+        .collect()
+}
+
+/// Returns true if the key was expired and got deleted
+pub(crate) fn delete_expired(storage: &mut StorageInner, key: &StorageKey) -> bool {
+    if !storage.get(key).is_some_and(|x| x.is_expired()) {
+        return false;
     }
+    storage.remove(key);
+    true
+}
 
-    pub(crate) fn delete_expired(&self, key: &StorageKey) {
-        let mut guard = self.inner.write().expect("got poisoned lock, can't handle that");
-        let Some(StorageItem::Simple(item)) = guard.get(key) else {
-            return;
-        };
-        if !item.is_expired() {
-            return;
-        }
-        guard.remove(key);
+fn random_index(size: usize) -> usize {
+    // each RandomState is seeded with random keys, which is good enough here
+    (RandomState::new().build_hasher().finish() % size as u64) as usize
+}
+
+/// None if there is no such key, or it does not expire
+pub(crate) fn get_expiry(storage: &StorageInner, key: &StorageKey) -> Option<ExpiryTs> {
+    match storage.get(key).map(|x| x.as_ref()) {
+        Some(StorageItem::Simple(item)) => item.expires_at,
+        _ => None,
+    }
+}
+
+/// Returns false if there is no such key
+pub(crate) fn set_expiry(storage: &mut StorageInner, key: &StorageKey, expires_at: ExpiryTs) -> bool {
+    match storage.get_mut(key).map(Arc::make_mut) {
+        Some(StorageItem::Simple(item)) if !item.is_expired() => {
+            item.expires_at = Some(expires_at);
+            true
+        },
+        Some(StorageItem::Stream(_)) => {
+            eprintln!("expiry of streams is not implemented yet");
+            false
+        },
+        _ => false,
     }
 }
 
@@ -100,6 +300,14 @@ pub(crate) enum StorageItem {
     Simple(StorageItemSimple),
     Stream(StorageItemStream),
 }
+impl StorageItem {
+    pub fn is_expired(&self) -> bool {
+        match self {
+            StorageItem::Simple(x) => x.is_expired(),
+            StorageItem::Stream(_) => false,
+        }
+    }
+}
 
 #[derive(Clone, Debug)]
 pub(crate) struct StorageItemSimple {
@@ -143,7 +351,92 @@ pub(crate) type StorageItemStream = Vec<StreamEntry>;
 #[derive(Clone, Debug)]
 pub(crate) struct StreamEntry {
     pub id: StreamEntryId,
-    #[allow(dead_code)]
     pub data: HashMap<StorageKey, BinaryData>,
 }
 pub(crate) type StreamEntryId = Vec<u8>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(expires_at: Option<ExpiryTs>) -> StorageItemSimple {
+        StorageItemSimple::from_data(b"v".to_vec(), expires_at)
+    }
+
+    #[test]
+    fn sample_volatile_keys_picks_only_keys_with_ttl() {
+        let expires_at = now_ts() + 60_000;
+        let mut inner = StorageInner::new();
+        for i in 0..30 {
+            set_string(&mut inner, format!("ttl{i}").into_bytes(), item(Some(expires_at)));
+        }
+        set_string(&mut inner, b"plain".to_vec(), item(None));
+        let storage = Storage::new(inner);
+
+        let (keys, forgotten) = storage.sample_volatile_keys(&storage.read_all(), 20);
+        assert_eq!(keys.len(), 20);
+        assert_eq!(forgotten, 0);
+        assert!(keys.iter().all(|key| key.starts_with(b"ttl")));
+        let mut unique = keys.clone();
+        unique.sort();
+        unique.dedup();
+        assert_eq!(unique.len(), 20);
+
+        // keys that lost their TTL are forgotten once they are picked
+        let mut guard = storage.write_all();
+        for i in 0..30 {
+            set_string(&mut guard, format!("ttl{i}").into_bytes(), item(None));
+        }
+        set_string(&mut guard, b"new".to_vec(), item(Some(expires_at)));
+        storage.add_volatile(&b"new".to_vec());
+        let (keys, forgotten) = storage.sample_volatile_keys(&guard, 100);
+        assert_eq!(keys, vec![b"new".to_vec()]);
+        assert_eq!(forgotten, 30);
+        assert_eq!(storage.sample_volatile_keys(&guard, 100), (vec![b"new".to_vec()], 0));
+    }
+
+    #[test]
+    fn write_after_watch_is_a_modification() {
+        let key = b"key".to_vec();
+        let storage = Storage::new(StorageInner::new());
+        let watched = storage.watch(&key);
+        assert!(!storage.is_modified(&storage.read_all(), &key, &watched));
+
+        let mut guard = storage.write_all();
+        set_string(&mut guard, key.clone(), item(None));
+        storage.touch(&key);
+        assert!(storage.is_modified(&guard, &key, &watched));
+    }
+
+    #[test]
+    fn deleting_a_key_expired_before_watch_is_not_a_modification() {
+        let key = b"key".to_vec();
+        let mut inner = StorageInner::new();
+        set_string(&mut inner, key.clone(), item(Some(now_ts() - 1)));
+        let storage = Storage::new(inner);
+        let watched = storage.watch(&key);
+
+        let mut guard = storage.write_all();
+        assert!(delete_expired(&mut guard, &key));
+        storage.touch_expired(&key);
+        assert!(!storage.is_modified(&guard, &key, &watched));
+    }
+
+    #[test]
+    fn key_that_expires_after_watch_is_a_modification() {
+        let key = b"key".to_vec();
+        let mut inner = StorageInner::new();
+        set_string(&mut inner, key.clone(), item(Some(now_ts() + 20)));
+        let storage = Storage::new(inner);
+        let watched = storage.watch(&key);
+        assert!(!storage.is_modified(&storage.read_all(), &key, &watched));
+
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        // it counts even before anybody deletes it
+        let mut guard = storage.write_all();
+        assert!(storage.is_modified(&guard, &key, &watched));
+        assert!(delete_expired(&mut guard, &key));
+        storage.touch_expired(&key);
+        assert!(storage.is_modified(&guard, &key, &watched));
+    }
+}
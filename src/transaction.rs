@@ -1,17 +1,62 @@
 use crate::command::Command;
-use crate::storage::{StorageItemSimple, StorageKey, StreamEntry};
+use crate::storage::{ExpiryTs, StorageItemSimple, StorageKey, StreamEntry, WatchedVersion};
 
 #[derive(Default, Debug)]
 pub(crate) struct Transaction {
     pub started: bool,
-    pub queue: Vec<QueuedCommand>,
+    /// commands are parsed again when EXEC is called
+    pub queue: Vec<Command>,
+    /// some of the queued commands were rejected, so EXEC will fail
+    pub has_errors: bool,
+    /// keys with their versions at the moment of WATCH
+    pub watched: Vec<(StorageKey, WatchedVersion)>,
 }
 
 #[derive(Debug)]
 pub(crate) enum QueuedCommand {
     // todo: think of some better way to replicate commands without saving them here
-    Set{key: StorageKey, item: StorageItemSimple, command: Command},
+    Set{key: StorageKey, item: StorageItemSimple, options: SetOptions, command: Command},
     Xadd{key: StorageKey, item: StreamEntry, command: Command},
     Incr{key: StorageKey, command: Command},
+    Del{keys: Vec<StorageKey>, command: Command},
+    Expire{key: StorageKey, expires_at: ExpiryTs, condition: ExpireCondition},
     Get{key: StorageKey},
-}
\ No newline at end of file
+    Keys,
+    Type{key: StorageKey},
+}
+
+/// The options of SET that are checked when it's executed
+#[derive(Debug, Default)]
+pub(crate) struct SetOptions {
+    pub condition: Option<SetCondition>,
+    /// reply with the old value
+    pub get: bool,
+    /// keep the expiry of the old value
+    pub keep_ttl: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SetCondition {
+    /// NX
+    IfMissing,
+    /// XX
+    IfExists,
+}
+
+/// NX / XX / GT / LT options of the EXPIRE commands, XX and GT / LT can be combined.
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct ExpireCondition {
+    pub nx: bool,
+    pub xx: bool,
+    pub gt: bool,
+    pub lt: bool,
+}
+impl ExpireCondition {
+    /// `current` is None for the keys that don't expire, same as in redis they are treated as having an infinite ttl
+    pub fn allows(&self, current: Option<ExpiryTs>, new: ExpiryTs) -> bool {
+        match current {
+            None => !self.xx && !self.gt,
+            Some(current) => !self.nx && (!self.gt || new > current) && (!self.lt || new < current),
+        }
+    }
+}